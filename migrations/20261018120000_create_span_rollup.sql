@@ -0,0 +1,74 @@
+-- Per-minute span aggregates, maintained at ingest time
+CREATE TABLE span_rollup_1m (
+    bucket TIMESTAMPTZ NOT NULL,
+    service_name TEXT NOT NULL DEFAULT '',
+    operation_name TEXT NOT NULL,
+    kind span_kind NOT NULL DEFAULT 'UNSPECIFIED',
+    span_count BIGINT NOT NULL DEFAULT 0,
+    error_count BIGINT NOT NULL DEFAULT 0,
+    duration_sum_ns BIGINT NOT NULL DEFAULT 0,
+    duration_min_ns BIGINT NOT NULL,
+    duration_max_ns BIGINT NOT NULL,
+    -- 64 log2-scaled buckets: index n counts durations in [2^n, 2^(n+1)) ns
+    duration_histogram BIGINT[] NOT NULL,
+    PRIMARY KEY (bucket, service_name, operation_name, kind)
+);
+
+CREATE INDEX idx_span_rollup_1m_service_name_bucket ON span_rollup_1m(service_name, bucket);
+
+WITH binned AS (
+    SELECT
+        DATE_BIN(INTERVAL '1 minute', started_at, '1970-01-01 00:00:00'::TIMESTAMPTZ) AS bucket,
+        COALESCE(service_name, '') AS service_name,
+        operation_name,
+        kind,
+        LEAST(FLOOR(LOG(2, GREATEST(duration_ns, 1)::NUMERIC))::INTEGER, 63) AS histogram_bucket
+    FROM span
+),
+
+histogram_counts AS (
+    SELECT bucket, service_name, operation_name, kind, histogram_bucket, COUNT(*) AS n
+    FROM binned
+    GROUP BY bucket, service_name, operation_name, kind, histogram_bucket
+),
+
+histograms AS (
+    SELECT
+        k.bucket,
+        k.service_name,
+        k.operation_name,
+        k.kind,
+        ARRAY_AGG(COALESCE(h.n, 0) ORDER BY i.idx) AS duration_histogram
+    FROM (SELECT DISTINCT bucket, service_name, operation_name, kind FROM histogram_counts) k
+    CROSS JOIN GENERATE_SERIES(0, 63) AS i(idx)
+    LEFT JOIN histogram_counts h
+        ON h.bucket = k.bucket
+        AND h.service_name = k.service_name
+        AND h.operation_name = k.operation_name
+        AND h.kind = k.kind
+        AND h.histogram_bucket = i.idx
+    GROUP BY k.bucket, k.service_name, k.operation_name, k.kind
+)
+
+INSERT INTO span_rollup_1m (
+    bucket, service_name, operation_name, kind, span_count, error_count,
+    duration_sum_ns, duration_min_ns, duration_max_ns, duration_histogram
+)
+SELECT
+    DATE_BIN(INTERVAL '1 minute', s.started_at, '1970-01-01 00:00:00'::TIMESTAMPTZ) AS bucket,
+    COALESCE(s.service_name, '') AS service_name,
+    s.operation_name,
+    s.kind,
+    COUNT(*),
+    COUNT(*) FILTER (WHERE s.status_code = 2),
+    SUM(s.duration_ns),
+    MIN(s.duration_ns),
+    MAX(s.duration_ns),
+    h.duration_histogram
+FROM span s
+JOIN histograms h
+    ON h.bucket = DATE_BIN(INTERVAL '1 minute', s.started_at, '1970-01-01 00:00:00'::TIMESTAMPTZ)
+    AND h.service_name = COALESCE(s.service_name, '')
+    AND h.operation_name = s.operation_name
+    AND h.kind = s.kind
+GROUP BY 1, 2, 3, 4, h.duration_histogram;
@@ -0,0 +1,42 @@
+-- Services, operations and span attribute keys seen at ingest, with first/last seen times.
+-- Spans without a service name are catalogued under ''.
+CREATE TABLE service_catalog (
+    service_name TEXT PRIMARY KEY,
+    first_seen TIMESTAMPTZ NOT NULL,
+    last_seen TIMESTAMPTZ NOT NULL
+);
+
+CREATE TABLE operation_catalog (
+    service_name TEXT NOT NULL,
+    operation_name TEXT NOT NULL,
+    kind span_kind NOT NULL DEFAULT 'UNSPECIFIED',
+    first_seen TIMESTAMPTZ NOT NULL,
+    last_seen TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (service_name, operation_name, kind)
+);
+
+CREATE TABLE span_attribute_catalog (
+    service_name TEXT NOT NULL,
+    key TEXT NOT NULL,
+    first_seen TIMESTAMPTZ NOT NULL,
+    last_seen TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (service_name, key)
+);
+
+CREATE INDEX idx_span_attribute_catalog_key ON span_attribute_catalog(key);
+
+INSERT INTO service_catalog (service_name, first_seen, last_seen)
+SELECT COALESCE(service_name, ''), MIN(started_at), MAX(started_at)
+FROM span
+GROUP BY COALESCE(service_name, '');
+
+INSERT INTO operation_catalog (service_name, operation_name, kind, first_seen, last_seen)
+SELECT COALESCE(service_name, ''), operation_name, kind, MIN(started_at), MAX(started_at)
+FROM span
+GROUP BY COALESCE(service_name, ''), operation_name, kind;
+
+INSERT INTO span_attribute_catalog (service_name, key, first_seen, last_seen)
+SELECT COALESCE(s.service_name, ''), a.key, MIN(s.started_at), MAX(s.started_at)
+FROM span s, LATERAL JSONB_OBJECT_KEYS(s.attributes) AS a(key)
+WHERE s.attributes IS NOT NULL
+GROUP BY COALESCE(s.service_name, ''), a.key;
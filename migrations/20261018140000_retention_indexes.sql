@@ -0,0 +1,9 @@
+-- Logs are retained independently of traces, so deleting a span or trace must not
+-- cascade to its logs.
+ALTER TABLE log DROP CONSTRAINT IF EXISTS log_span_id_fkey;
+ALTER TABLE log DROP CONSTRAINT IF EXISTS log_trace_id_fkey;
+
+CREATE INDEX idx_span_started_at ON span(started_at);
+CREATE INDEX idx_span_service_name_started_at ON span(service_name, started_at);
+CREATE INDEX idx_trace_ended_at ON trace(ended_at);
+CREATE INDEX idx_log_service_name_timestamp ON log(service_name, timestamp);
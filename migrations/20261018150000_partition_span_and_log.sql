@@ -0,0 +1,113 @@
+-- Daily range partitions for span (by started_at), log and log_attribute (by timestamp).
+-- Rows outside every daily partition land in <table>_default until a partition for
+-- their day is created, at which point create_daily_partition moves them over.
+CREATE FUNCTION create_daily_partition(parent TEXT, day DATE) RETURNS VOID AS $$
+DECLARE
+    partition_name TEXT := parent || '_p' || TO_CHAR(day, 'YYYYMMDD');
+    partition_key TEXT;
+    lower_bound TIMESTAMPTZ := day::TIMESTAMP AT TIME ZONE 'UTC';
+    upper_bound TIMESTAMPTZ := (day + 1)::TIMESTAMP AT TIME ZONE 'UTC';
+BEGIN
+    IF TO_REGCLASS(partition_name) IS NOT NULL THEN
+        RETURN;
+    END IF;
+
+    SELECT a.attname INTO partition_key
+    FROM pg_partitioned_table pt
+    JOIN pg_attribute a ON a.attrelid = pt.partrelid AND a.attnum = pt.partattrs[0]
+    WHERE pt.partrelid = parent::REGCLASS;
+
+    EXECUTE FORMAT('CREATE TABLE %I (LIKE %I INCLUDING DEFAULTS)', partition_name, parent);
+    EXECUTE FORMAT(
+        'WITH moved AS (DELETE FROM %I WHERE %I >= $1 AND %I < $2 RETURNING *) '
+        'INSERT INTO %I SELECT * FROM moved',
+        parent || '_default', partition_key, partition_key, partition_name
+    ) USING lower_bound, upper_bound;
+    EXECUTE FORMAT(
+        'ALTER TABLE %I ATTACH PARTITION %I FOR VALUES FROM (%L) TO (%L)',
+        parent, partition_name, lower_bound, upper_bound
+    );
+END;
+$$ LANGUAGE plpgsql;
+
+-- Spans
+ALTER TABLE span RENAME TO span_unpartitioned;
+
+CREATE TABLE span (LIKE span_unpartitioned INCLUDING DEFAULTS) PARTITION BY RANGE (started_at);
+CREATE TABLE span_default PARTITION OF span DEFAULT;
+
+DO $$
+DECLARE
+    day DATE;
+BEGIN
+    FOR day IN
+        SELECT DISTINCT (started_at AT TIME ZONE 'UTC')::DATE FROM span_unpartitioned
+    LOOP
+        PERFORM create_daily_partition('span', day);
+    END LOOP;
+END;
+$$;
+
+INSERT INTO span SELECT * FROM span_unpartitioned;
+DROP TABLE span_unpartitioned;
+
+ALTER TABLE span ADD PRIMARY KEY (id, started_at);
+ALTER TABLE span ADD CONSTRAINT span_trace_id_fkey FOREIGN KEY (trace_id) REFERENCES trace(id);
+
+CREATE INDEX idx_span_trace_id_started_at ON span(trace_id, started_at);
+CREATE INDEX idx_span_operation_name ON span(operation_name);
+CREATE INDEX idx_span_duration_ns ON span(duration_ns);
+CREATE INDEX idx_span_status_code ON span(status_code);
+CREATE INDEX idx_trace_service_name ON span(service_name);
+CREATE INDEX idx_span_attributes ON span USING GIN (attributes);
+CREATE INDEX idx_span_started_at ON span(started_at);
+CREATE INDEX idx_span_service_name_started_at ON span(service_name, started_at);
+
+-- Logs
+ALTER TABLE log RENAME TO log_unpartitioned;
+ALTER TABLE log_attribute RENAME TO log_attribute_unpartitioned;
+
+CREATE TABLE log (LIKE log_unpartitioned INCLUDING DEFAULTS) PARTITION BY RANGE (timestamp);
+CREATE TABLE log_default PARTITION OF log DEFAULT;
+
+-- Attributes carry their log's timestamp so they can be partitioned (and dropped)
+-- alongside it.
+CREATE TABLE log_attribute (
+    id UUID DEFAULT gen_random_uuid(),
+    log_id UUID NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT NOW(),
+    timestamp TIMESTAMPTZ NOT NULL
+) PARTITION BY RANGE (timestamp);
+CREATE TABLE log_attribute_default PARTITION OF log_attribute DEFAULT;
+
+DO $$
+DECLARE
+    day DATE;
+BEGIN
+    FOR day IN
+        SELECT DISTINCT (timestamp AT TIME ZONE 'UTC')::DATE FROM log_unpartitioned
+    LOOP
+        PERFORM create_daily_partition('log', day);
+        PERFORM create_daily_partition('log_attribute', day);
+    END LOOP;
+END;
+$$;
+
+INSERT INTO log SELECT * FROM log_unpartitioned;
+INSERT INTO log_attribute (id, log_id, key, value, created_at, timestamp)
+SELECT a.id, a.log_id, a.key, a.value, a.created_at, l.timestamp
+FROM log_attribute_unpartitioned a
+JOIN log_unpartitioned l ON l.id = a.log_id;
+
+DROP TABLE log_attribute_unpartitioned;
+DROP TABLE log_unpartitioned;
+
+ALTER TABLE log ADD PRIMARY KEY (id, timestamp);
+ALTER TABLE log_attribute ADD PRIMARY KEY (id, timestamp);
+
+CREATE INDEX idx_log_trace_id_span_id ON log(trace_id, span_id);
+CREATE INDEX idx_log_timestamp ON log(timestamp);
+CREATE INDEX idx_log_service_name_timestamp ON log(service_name, timestamp);
+CREATE INDEX idx_log_attribute_log_id ON log_attribute(log_id);
@@ -0,0 +1,8 @@
+-- Span ids are only unique within a trace, and retried exports resend spans that are
+-- already stored, so the key includes the trace id and inserts skip existing spans.
+ALTER TABLE span DROP CONSTRAINT span_pkey;
+ALTER TABLE span ADD PRIMARY KEY (trace_id, id, started_at);
+
+-- Spans are inserted before their trace row, so that only newly stored spans count
+-- towards it, and the trace is checked at commit.
+ALTER TABLE span ALTER CONSTRAINT span_trace_id_fkey DEFERRABLE INITIALLY DEFERRED;
@@ -0,0 +1,12 @@
+-- API keys, stored as bcrypt hashes and looked up by their non-secret prefix.
+-- Scopes are 'ingest', 'read' and 'admin'.
+CREATE TABLE api_key (
+    id UUID PRIMARY KEY,
+    name TEXT NOT NULL,
+    prefix TEXT NOT NULL UNIQUE,
+    key_hash TEXT NOT NULL,
+    scopes TEXT[] NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_used_at TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ
+);
@@ -0,0 +1,39 @@
+-- Traces, spans and logs belong to a tenant, and everything keyed by trace or service is
+-- keyed by tenant first. Existing data belongs to 'default', the tenant used for
+-- requests that don't name one.
+ALTER TABLE span DROP CONSTRAINT span_trace_id_fkey;
+
+ALTER TABLE trace ADD COLUMN tenant_id TEXT NOT NULL DEFAULT 'default';
+ALTER TABLE trace DROP CONSTRAINT trace_pkey;
+ALTER TABLE trace ADD PRIMARY KEY (tenant_id, id);
+CREATE INDEX idx_trace_tenant_id_started_at ON trace(tenant_id, started_at);
+
+ALTER TABLE span ADD COLUMN tenant_id TEXT NOT NULL DEFAULT 'default';
+ALTER TABLE span DROP CONSTRAINT span_pkey;
+ALTER TABLE span ADD PRIMARY KEY (tenant_id, trace_id, id, started_at);
+ALTER TABLE span ADD CONSTRAINT span_trace_id_fkey
+    FOREIGN KEY (tenant_id, trace_id) REFERENCES trace(tenant_id, id)
+    DEFERRABLE INITIALLY DEFERRED;
+CREATE INDEX idx_span_tenant_id_started_at ON span(tenant_id, started_at);
+
+ALTER TABLE log ADD COLUMN tenant_id TEXT NOT NULL DEFAULT 'default';
+CREATE INDEX idx_log_tenant_id_timestamp ON log(tenant_id, timestamp);
+
+ALTER TABLE span_rollup_1m ADD COLUMN tenant_id TEXT NOT NULL DEFAULT 'default';
+ALTER TABLE span_rollup_1m DROP CONSTRAINT span_rollup_1m_pkey;
+ALTER TABLE span_rollup_1m ADD PRIMARY KEY (tenant_id, bucket, service_name, operation_name, kind);
+
+ALTER TABLE service_catalog ADD COLUMN tenant_id TEXT NOT NULL DEFAULT 'default';
+ALTER TABLE service_catalog DROP CONSTRAINT service_catalog_pkey;
+ALTER TABLE service_catalog ADD PRIMARY KEY (tenant_id, service_name);
+
+ALTER TABLE operation_catalog ADD COLUMN tenant_id TEXT NOT NULL DEFAULT 'default';
+ALTER TABLE operation_catalog DROP CONSTRAINT operation_catalog_pkey;
+ALTER TABLE operation_catalog ADD PRIMARY KEY (tenant_id, service_name, operation_name, kind);
+
+ALTER TABLE span_attribute_catalog ADD COLUMN tenant_id TEXT NOT NULL DEFAULT 'default';
+ALTER TABLE span_attribute_catalog DROP CONSTRAINT span_attribute_catalog_pkey;
+ALTER TABLE span_attribute_catalog ADD PRIMARY KEY (tenant_id, service_name, key);
+
+-- Keys without a tenant may pick one per request.
+ALTER TABLE api_key ADD COLUMN tenant_id TEXT;
@@ -0,0 +1,8 @@
+-- Log attributes belong to their log's tenant, so they're deleted with the right log
+-- even if two tenants' logs share an id.
+ALTER TABLE log_attribute ADD COLUMN tenant_id TEXT NOT NULL DEFAULT 'default';
+
+UPDATE log_attribute a
+SET tenant_id = l.tenant_id
+FROM log l
+WHERE l.id = a.log_id AND l.timestamp = a.timestamp AND l.tenant_id <> 'default';
@@ -0,0 +1,37 @@
+-- Locks the parent while rows move out of the default partition, so rows inserted for
+-- the same day in the meantime can't make the ATTACH fail.
+CREATE OR REPLACE FUNCTION create_daily_partition(parent TEXT, day DATE) RETURNS VOID AS $$
+DECLARE
+    partition_name TEXT := parent || '_p' || TO_CHAR(day, 'YYYYMMDD');
+    partition_key TEXT;
+    lower_bound TIMESTAMPTZ := day::TIMESTAMP AT TIME ZONE 'UTC';
+    upper_bound TIMESTAMPTZ := (day + 1)::TIMESTAMP AT TIME ZONE 'UTC';
+BEGIN
+    IF TO_REGCLASS(partition_name) IS NOT NULL THEN
+        RETURN;
+    END IF;
+
+    -- Blocks writes to the parent until the calling transaction ends, and serializes
+    -- concurrent calls, which is why the partition is looked up again.
+    EXECUTE FORMAT('LOCK TABLE %I IN SHARE ROW EXCLUSIVE MODE', parent);
+    IF TO_REGCLASS(partition_name) IS NOT NULL THEN
+        RETURN;
+    END IF;
+
+    SELECT a.attname INTO partition_key
+    FROM pg_partitioned_table pt
+    JOIN pg_attribute a ON a.attrelid = pt.partrelid AND a.attnum = pt.partattrs[0]
+    WHERE pt.partrelid = parent::REGCLASS;
+
+    EXECUTE FORMAT('CREATE TABLE %I (LIKE %I INCLUDING DEFAULTS)', partition_name, parent);
+    EXECUTE FORMAT(
+        'WITH moved AS (DELETE FROM %I WHERE %I >= $1 AND %I < $2 RETURNING *) '
+        'INSERT INTO %I SELECT * FROM moved',
+        parent || '_default', partition_key, partition_key, partition_name
+    ) USING lower_bound, upper_bound;
+    EXECUTE FORMAT(
+        'ALTER TABLE %I ATTACH PARTITION %I FOR VALUES FROM (%L) TO (%L)',
+        parent, partition_name, lower_bound, upper_bound
+    );
+END;
+$$ LANGUAGE plpgsql;
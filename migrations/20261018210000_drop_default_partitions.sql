@@ -0,0 +1,51 @@
+-- Expired partitions are detached concurrently before they're dropped, which Postgres
+-- doesn't allow while a default partition exists. Rows in the default partitions move
+-- to their day's partition, and writers create a day's partition before writing to it.
+DO $$
+DECLARE
+    parent TEXT;
+    partition_key TEXT;
+    day DATE;
+BEGIN
+    FOREACH parent IN ARRAY ARRAY['span', 'log', 'log_attribute'] LOOP
+        SELECT a.attname INTO partition_key
+        FROM pg_partitioned_table pt
+        JOIN pg_attribute a ON a.attrelid = pt.partrelid AND a.attnum = pt.partattrs[0]
+        WHERE pt.partrelid = parent::REGCLASS;
+
+        FOR day IN EXECUTE FORMAT(
+            'SELECT DISTINCT (%I AT TIME ZONE ''UTC'')::DATE FROM %I',
+            partition_key, parent || '_default'
+        ) LOOP
+            PERFORM create_daily_partition(parent, day);
+        END LOOP;
+
+        EXECUTE FORMAT('DROP TABLE %I', parent || '_default');
+    END LOOP;
+END;
+$$;
+
+CREATE OR REPLACE FUNCTION create_daily_partition(parent TEXT, day DATE) RETURNS VOID AS $$
+DECLARE
+    partition_name TEXT := parent || '_p' || TO_CHAR(day, 'YYYYMMDD');
+    lower_bound TIMESTAMPTZ := day::TIMESTAMP AT TIME ZONE 'UTC';
+    upper_bound TIMESTAMPTZ := (day + 1)::TIMESTAMP AT TIME ZONE 'UTC';
+BEGIN
+    IF TO_REGCLASS(partition_name) IS NOT NULL THEN
+        RETURN;
+    END IF;
+
+    -- Serializes concurrent calls without blocking writes to the parent, which is why
+    -- the partition is looked up again.
+    EXECUTE FORMAT('LOCK TABLE %I IN SHARE UPDATE EXCLUSIVE MODE', parent);
+    IF TO_REGCLASS(partition_name) IS NOT NULL THEN
+        RETURN;
+    END IF;
+
+    EXECUTE FORMAT('CREATE TABLE %I (LIKE %I INCLUDING DEFAULTS)', partition_name, parent);
+    EXECUTE FORMAT(
+        'ALTER TABLE %I ATTACH PARTITION %I FOR VALUES FROM (%L) TO (%L)',
+        parent, partition_name, lower_bound, upper_bound
+    );
+END;
+$$ LANGUAGE plpgsql;
@@ -1,35 +1,160 @@
-use core::panic;
 use std::collections::HashMap;
 use std::sync::Arc;
 
 use axum::body::Bytes;
-use axum::extract::{Path, Query, State};
-use axum::http::{HeaderMap, StatusCode};
-use axum::routing::{get, post};
+use axum::extract::{FromRef, Path, Query, Request, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
 use axum::{Json, Router};
-use opentelemetry_proto::tonic::collector::logs::v1::ExportLogsServiceRequest;
+use opentelemetry_proto::tonic::collector::logs::v1::{
+    ExportLogsPartialSuccess, ExportLogsServiceRequest, ExportLogsServiceResponse,
+};
 use prost::Message;
 use serde::{Deserialize, Serialize};
-use serde_json::{Value, json};
+use serde_json::Value;
 use sqlx::{PgPool, Postgres, QueryBuilder, Row};
 
-use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+use opentelemetry_proto::tonic::collector::trace::v1::{
+    ExportTracePartialSuccess, ExportTraceServiceRequest, ExportTraceServiceResponse,
+};
 
+mod auth;
+mod cors;
 mod crud;
-
-pub use crud::{
-    flatten_logs_and_attrs, flatten_spans, insert_log_attributes, insert_logs, insert_spans,
-    insert_traces,
-};
+mod formula;
+mod head_sampling;
+mod ingest_queue;
+mod otlp_error;
+mod pg_copy;
+mod processors;
+mod redaction;
+mod rollup;
+mod span_validation;
+mod spool;
+mod tail_sampling;
+mod tenancy;
+mod trace_aggregate;
+mod trace_compare;
+mod trace_tree;
+
+pub use auth::{Auth, AuthConfig};
+pub use cors::CorsConfig;
+pub use crud::{flatten_logs_and_attrs, flatten_spans};
+pub use head_sampling::{HeadSampler, HeadSamplingConfig};
+pub use ingest_queue::{IngestQueue, IngestQueueConfig};
+pub use processors::{ProcessorConfig, Processors};
+pub use redaction::{RedactionConfig, Redactor};
+pub use span_validation::{SpanValidationConfig, SpanValidator};
+pub use spool::{Spool, SpoolConfig};
+pub use tail_sampling::{TailSampler, TailSamplingConfig};
+pub use tenancy::{Tenancy, TenancyConfig};
 use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
 
+use crate::handlers::auth::{ApiKey, AuthError, AuthenticatedKey, CreatedApiKey, Scope};
 use crate::handlers::crud::{SpanAttributeValue, WriteableLog, WriteableSpan, WriteableTrace};
+use crate::handlers::head_sampling::IngestStats;
+use crate::handlers::ingest_queue::{QueueFull, RejectedStats};
+use crate::handlers::otlp_error::OtlpError;
+use crate::handlers::span_validation::ValidationStats;
+use crate::handlers::spool::SpoolStats;
+use crate::handlers::tenancy::{Tenant, TenantError, validate_tenant};
+use crate::handlers::trace_aggregate::{AggregateTrace, aggregate_trace_trees};
+use crate::handlers::trace_compare::{TraceComparison, compare_trace_trees};
+use crate::handlers::trace_tree::{TraceTree, build_trace_tree};
+
+#[derive(Clone)]
+pub struct OtelState {
+    pub pool: Arc<PgPool>,
+    pub ingest_queue: Arc<IngestQueue>,
+    pub head_sampler: Arc<HeadSampler>,
+    pub tail_sampler: Option<Arc<TailSampler>>,
+    pub span_validator: Arc<SpanValidator>,
+    pub processors: Arc<Processors>,
+    pub redactor: Arc<Redactor>,
+    pub auth: Arc<Auth>,
+    pub tenancy: Arc<Tenancy>,
+}
+
+impl FromRef<OtelState> for Arc<PgPool> {
+    fn from_ref(state: &OtelState) -> Self {
+        state.pool.clone()
+    }
+}
+
+impl FromRef<OtelState> for Arc<Tenancy> {
+    fn from_ref(state: &OtelState) -> Self {
+        state.tenancy.clone()
+    }
+}
+
+#[derive(Clone)]
+pub struct ApiState {
+    pub pool: Arc<PgPool>,
+    pub ingest_queue: Arc<IngestQueue>,
+    pub head_sampler: Arc<HeadSampler>,
+    pub span_validator: Arc<SpanValidator>,
+    pub auth: Arc<Auth>,
+    pub tenancy: Arc<Tenancy>,
+}
+
+impl FromRef<ApiState> for Arc<PgPool> {
+    fn from_ref(state: &ApiState) -> Self {
+        state.pool.clone()
+    }
+}
+
+impl FromRef<ApiState> for Arc<Tenancy> {
+    fn from_ref(state: &ApiState) -> Self {
+        state.tenancy.clone()
+    }
+}
+
+fn protobuf_response<M: Message>(message: M) -> Response {
+    (
+        [(header::CONTENT_TYPE, "application/x-protobuf")],
+        message.encode_to_vec(),
+    )
+        .into_response()
+}
+
+/// Describes the items skipped from an export, for `partial_success.error_message`.
+fn rejection_message(errors: &[tonic::Status]) -> String {
+    match errors {
+        [] => String::new(),
+        [error] => error.message().to_string(),
+        [error, rest @ ..] => format!("{} (and {} more)", error.message(), rest.len()),
+    }
+}
+
+/// The tenant an export is for, with rejections as OTLP errors.
+fn export_tenant(tenant: Result<Tenant, TenantError>) -> Result<String, OtlpError> {
+    match tenant {
+        Ok(Tenant(tenant)) => Ok(tenant),
+        Err(e @ TenantError::Invalid) => Err(OtlpError::invalid_argument(e.to_string())),
+        Err(e @ TenantError::Forbidden) => Err(OtlpError::permission_denied(e.to_string())),
+    }
+}
+
+/// Turns exports away while queued batches can't be written or spooled, so clients
+/// hold on to them and retry instead of growing the queue.
+fn check_accepting(ingest_queue: &IngestQueue) -> Result<(), OtlpError> {
+    if ingest_queue.accepting() {
+        Ok(())
+    } else {
+        Err(OtlpError::unavailable(ingest_queue.retry_after()))
+    }
+}
 
 pub async fn insert_traces_handler(
-    State(pool): State<Arc<PgPool>>,
+    State(state): State<OtelState>,
+    tenant: Result<Tenant, TenantError>,
     headers: HeaderMap,
     body: Bytes,
-) -> Result<Json<Value>, StatusCode> {
+) -> Result<Response, OtlpError> {
+    let tenant = export_tenant(tenant)?;
     let content_type = headers
         .get("content-type")
         .and_then(|v| v.to_str().ok())
@@ -38,60 +163,87 @@ pub async fn insert_traces_handler(
     if !content_type.contains("application/x-protobuf")
         && !content_type.contains("application/protobuf")
     {
-        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        return Err(OtlpError::unsupported_media_type(content_type));
     }
 
-    let payload = ExportTraceServiceRequest::decode(body).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let payload = ExportTraceServiceRequest::decode(body)
+        .map_err(|e| OtlpError::invalid_argument(format!("Invalid payload: {}", e)))?;
 
-    let mut tx = pool
-        .begin()
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    check_accepting(&state.ingest_queue)?;
 
-    let (traces, spans) = flatten_spans(&payload).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let (spans, mut errors) = flatten_spans(&payload, &tenant, &state.processors, &state.redactor);
+    let (spans, invalid) = state.span_validator.validate(spans);
+    errors.extend(invalid);
+    let spans = state.head_sampler.filter(spans);
 
-    insert_traces(&traces, &mut tx)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    insert_spans(&spans, &mut tx)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let span_count = spans.len();
+    if !state.tenancy.take_spans(&tenant, span_count) {
+        return Err(OtlpError::quota_exceeded(&tenant));
+    }
 
-    tx.commit()
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let pushed = match &state.tail_sampler {
+        Some(tail_sampler) => tail_sampler.add(spans),
+        None => state
+            .ingest_queue
+            .try_push_spans(spans)
+            .map_err(|_| QueueFull),
+    };
+    if pushed.is_err() {
+        // The client retries them, so they shouldn't count against the quota twice.
+        state.tenancy.refund_spans(&tenant, span_count);
+        return Err(OtlpError::resource_exhausted(
+            state.ingest_queue.retry_after(),
+        ));
+    }
 
-    Ok(Json(json!({ "status": "success" })))
+    let partial_success = (!errors.is_empty()).then(|| ExportTracePartialSuccess {
+        rejected_spans: errors.len() as i64,
+        error_message: rejection_message(&errors),
+    });
+
+    Ok(protobuf_response(ExportTraceServiceResponse {
+        partial_success,
+    }))
 }
 
 pub async fn insert_logs_handler(
-    State(pool): State<Arc<PgPool>>,
+    State(state): State<OtelState>,
+    tenant: Result<Tenant, TenantError>,
     body: Bytes,
-) -> Result<Json<Value>, StatusCode> {
-    use prost::Message;
-    let payload =
-        ExportLogsServiceRequest::decode(&body[..]).map_err(|_| StatusCode::BAD_REQUEST)?;
+) -> Result<Response, OtlpError> {
+    let tenant = export_tenant(tenant)?;
+    let payload = ExportLogsServiceRequest::decode(&body[..])
+        .map_err(|e| OtlpError::invalid_argument(format!("Invalid payload: {}", e)))?;
 
-    let mut tx = pool
-        .begin()
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    check_accepting(&state.ingest_queue)?;
 
-    let (logs, log_attributes) =
-        flatten_logs_and_attrs(&payload).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let (logs, log_attributes, errors) =
+        flatten_logs_and_attrs(&payload, &tenant, &state.processors, &state.redactor);
 
-    insert_logs(&logs, &mut tx)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    insert_log_attributes(&log_attributes, &mut tx)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let log_count = logs.len();
+    if !state.tenancy.take_logs(&tenant, log_count) {
+        return Err(OtlpError::quota_exceeded(&tenant));
+    }
 
-    tx.commit()
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if state
+        .ingest_queue
+        .try_push_logs(logs, log_attributes)
+        .is_err()
+    {
+        state.tenancy.refund_logs(&tenant, log_count);
+        return Err(OtlpError::resource_exhausted(
+            state.ingest_queue.retry_after(),
+        ));
+    }
+
+    let partial_success = (!errors.is_empty()).then(|| ExportLogsPartialSuccess {
+        rejected_log_records: errors.len() as i64,
+        error_message: rejection_message(&errors),
+    });
 
-    Ok(Json(json!({ "status": "success" })))
+    Ok(protobuf_response(ExportLogsServiceResponse {
+        partial_success,
+    }))
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -108,12 +260,17 @@ pub struct SearchTracesQuery {
     max_duration_ns: Option<i64>,
     status_code: Option<i32>,
     span_attributes: Option<String>, // Change to String for JSON parsing
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    start: Option<OffsetDateTime>, // Only traces starting at or after this time
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    end: Option<OffsetDateTime>, // Only traces starting before this time
     offset: Option<i64>,
     limit: Option<i64>,
 }
 
 pub async fn search_traces_handler(
     State(pool): State<Arc<PgPool>>,
+    Tenant(tenant): Tenant,
     Query(query): Query<SearchTracesQuery>,
 ) -> Result<Json<Vec<WriteableTrace>>, StatusCode> {
     let mut span_attribute_names: Vec<String> = Vec::new();
@@ -143,19 +300,28 @@ pub async fn search_traces_handler(
         )
 
         SELECT DISTINCT
+            t.tenant_id,
             t.id,
             t.started_at,
             t.ended_at,
             t.duration_ns,
             t.span_count
         FROM trace t
-        LEFT JOIN span s ON t.id = s.trace_id
+        LEFT JOIN span s
+            ON t.tenant_id = s.tenant_id
+            AND t.id = s.trace_id
+            -- A trace's spans never start before the trace does; bounding them lets
+            -- Postgres skip older partitions.
+            AND s.started_at >= COALESCE($10::TIMESTAMPTZ, '-infinity')
         WHERE
-            ($1::TEXT IS NULL OR s.service_name = $1::TEXT)
+            t.tenant_id = $12
+            AND ($1::TEXT IS NULL OR s.service_name = $1::TEXT)
             AND ($2::TEXT IS NULL OR s.operation_name = $2::TEXT)
             AND ($3::BIGINT IS NULL OR t.duration_ns >= $3::BIGINT)
             AND ($4::BIGINT IS NULL OR t.duration_ns <= $4::BIGINT)
             AND ($5::INTEGER IS NULL OR s.status_code = $5::INTEGER)
+            AND ($10::TIMESTAMPTZ IS NULL OR t.started_at >= $10::TIMESTAMPTZ)
+            AND ($11::TIMESTAMPTZ IS NULL OR t.started_at < $11::TIMESTAMPTZ)
             AND (
                 $6::TEXT[] IS NULL OR
                 NOT EXISTS (
@@ -179,6 +345,9 @@ pub async fn search_traces_handler(
         &span_attribute_values,
         query.limit.unwrap_or(100),
         query.offset.unwrap_or(0),
+        query.start,
+        query.end,
+        tenant,
     )
     .fetch_all(&*pool)
     .await
@@ -187,11 +356,10 @@ pub async fn search_traces_handler(
     let traces: Vec<WriteableTrace> = records
         .into_iter()
         .map(|record| WriteableTrace {
+            tenant_id: record.tenant_id,
             trace_id: record.id,
-            start_time: record
-                .started_at
-                .unwrap_or_else(|| OffsetDateTime::now_utc()),
-            end_time: record.ended_at.unwrap_or_else(|| OffsetDateTime::now_utc()),
+            start_time: record.started_at.unwrap_or_else(OffsetDateTime::now_utc),
+            end_time: record.ended_at.unwrap_or_else(OffsetDateTime::now_utc),
             duration_ns: record.duration_ns,
             span_count: record.span_count,
         })
@@ -202,19 +370,22 @@ pub async fn search_traces_handler(
 
 pub async fn get_trace_handler(
     State(pool): State<Arc<PgPool>>,
+    Tenant(tenant): Tenant,
     Path(trace_id): axum::extract::Path<String>,
 ) -> Result<Json<WriteableTrace>, StatusCode> {
     let maybe_record = sqlx::query!(
         r#"
         SELECT
+            tenant_id,
             id,
             started_at,
             ended_at,
             duration_ns,
             span_count
         FROM trace
-        WHERE id = $1
+        WHERE tenant_id = $1 AND id = $2
         "#,
+        tenant,
         trace_id
     )
     .fetch_optional(&*pool)
@@ -228,11 +399,10 @@ pub async fn get_trace_handler(
     let record = maybe_record.unwrap();
 
     let trace = WriteableTrace {
+        tenant_id: record.tenant_id,
         trace_id: record.id,
-        start_time: record
-            .started_at
-            .unwrap_or_else(|| OffsetDateTime::now_utc()),
-        end_time: record.ended_at.unwrap_or_else(|| OffsetDateTime::now_utc()),
+        start_time: record.started_at.unwrap_or_else(OffsetDateTime::now_utc),
+        end_time: record.ended_at.unwrap_or_else(OffsetDateTime::now_utc),
         duration_ns: record.duration_ns,
         span_count: record.span_count,
     };
@@ -267,10 +437,12 @@ fn json_to_span_attributes(json: Option<Value>) -> HashMap<String, SpanAttribute
 
 pub async fn list_spans_handler(
     State(pool): State<Arc<PgPool>>,
+    Tenant(tenant): Tenant,
 ) -> Result<Json<Vec<WriteableSpan>>, StatusCode> {
     let records = sqlx::query!(
         r#"
         SELECT
+            tenant_id,
             id,
             trace_id,
             parent_span_id,
@@ -284,9 +456,11 @@ pub async fn list_spans_handler(
             instrumentation_library,
             attributes
         FROM span
+        WHERE tenant_id = $1
         ORDER BY started_at DESC
         LIMIT 100
-        "#
+        "#,
+        tenant
     )
     .fetch_all(&*pool)
     .await
@@ -295,6 +469,7 @@ pub async fn list_spans_handler(
     let spans: Vec<WriteableSpan> = records
         .into_iter()
         .map(|record| WriteableSpan {
+            tenant_id: record.tenant_id,
             span_id: record.id,
             trace_id: record.trace_id,
             parent_span_id: record.parent_span_id,
@@ -316,10 +491,12 @@ pub async fn list_spans_handler(
 
 pub async fn list_logs_handler(
     State(pool): State<Arc<PgPool>>,
+    Tenant(tenant): Tenant,
 ) -> Result<Json<Vec<WriteableLog>>, StatusCode> {
     let records = sqlx::query!(
         r#"
         SELECT
+            tenant_id,
             id,
             trace_id,
             span_id,
@@ -331,9 +508,11 @@ pub async fn list_logs_handler(
             instrumentation_library,
             service_name
         FROM log
+        WHERE tenant_id = $1
         ORDER BY timestamp DESC
         LIMIT 100
-        "#
+        "#,
+        tenant
     )
     .fetch_all(&*pool)
     .await
@@ -342,6 +521,7 @@ pub async fn list_logs_handler(
     let logs: Vec<WriteableLog> = records
         .into_iter()
         .map(|record| WriteableLog {
+            tenant_id: record.tenant_id,
             log_id: record.id,
             trace_id: record.trace_id,
             span_id: record.span_id,
@@ -358,13 +538,24 @@ pub async fn list_logs_handler(
     Ok(Json(logs))
 }
 
-pub async fn get_trace_spans_handler(
-    State(pool): State<Arc<PgPool>>,
-    Path(trace_id): axum::extract::Path<String>,
-) -> Result<Json<Vec<WriteableSpan>>, StatusCode> {
+async fn fetch_trace_spans(
+    pool: &PgPool,
+    tenant: &str,
+    trace_id: &str,
+) -> Result<Vec<WriteableSpan>, sqlx::Error> {
+    fetch_spans_for_traces(pool, tenant, &[trace_id.to_string()]).await
+}
+
+/// Spans of the tenant's `trace_ids`, ordered by trace and then start time.
+async fn fetch_spans_for_traces(
+    pool: &PgPool,
+    tenant: &str,
+    trace_ids: &[String],
+) -> Result<Vec<WriteableSpan>, sqlx::Error> {
     let records = sqlx::query!(
         r#"
         SELECT
+            tenant_id,
             id,
             trace_id,
             parent_span_id,
@@ -374,22 +565,33 @@ pub async fn get_trace_spans_handler(
             duration_ns,
             status_code,
             status_message,
+            kind AS "kind: crud::DbSpanKind",
             instrumentation_library,
             service_name,
             attributes
         FROM span
-        WHERE trace_id = $1
-        ORDER BY started_at ASC
+        WHERE
+            tenant_id = $1
+            AND trace_id = ANY($2)
+            -- Bounding by the traces' time range lets Postgres skip other partitions.
+            AND started_at >= (
+                SELECT MIN(started_at) FROM trace WHERE tenant_id = $1 AND id = ANY($2)
+            )
+            AND started_at <= (
+                SELECT MAX(ended_at) FROM trace WHERE tenant_id = $1 AND id = ANY($2)
+            )
+        ORDER BY trace_id, started_at ASC
         "#,
-        trace_id
+        tenant,
+        trace_ids
     )
-    .fetch_all(&*pool)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    .fetch_all(pool)
+    .await?;
 
     let spans: Vec<WriteableSpan> = records
         .into_iter()
         .map(|record| WriteableSpan {
+            tenant_id: record.tenant_id,
             span_id: record.id,
             trace_id: record.trace_id,
             parent_span_id: record.parent_span_id,
@@ -399,41 +601,388 @@ pub async fn get_trace_spans_handler(
             duration_ns: record.duration_ns,
             status_code: record.status_code,
             status_message: record.status_message,
-            span_kind: crud::DbSpanKind::Unspecified,
+            span_kind: record.kind,
             instrumentation_library: record.instrumentation_library,
             service_name: record.service_name,
             attributes: json_to_span_attributes(record.attributes),
         })
         .collect();
 
+    Ok(spans)
+}
+
+pub async fn get_trace_spans_handler(
+    State(pool): State<Arc<PgPool>>,
+    Tenant(tenant): Tenant,
+    Path(trace_id): axum::extract::Path<String>,
+) -> Result<Json<Vec<WriteableSpan>>, StatusCode> {
+    let spans = fetch_trace_spans(&pool, &tenant, &trace_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
     Ok(Json(spans))
 }
 
+pub async fn get_trace_tree_handler(
+    State(pool): State<Arc<PgPool>>,
+    Tenant(tenant): Tenant,
+    Path(trace_id): axum::extract::Path<String>,
+) -> Result<Json<TraceTree>, StatusCode> {
+    let spans = fetch_trace_spans(&pool, &tenant, &trace_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if spans.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    Ok(Json(build_trace_tree(trace_id, &spans)))
+}
+
+pub async fn compare_traces_handler(
+    State(pool): State<Arc<PgPool>>,
+    Tenant(tenant): Tenant,
+    Path((trace_id, other_trace_id)): Path<(String, String)>,
+) -> Result<Json<TraceComparison>, StatusCode> {
+    let base_spans = fetch_trace_spans(&pool, &tenant, &trace_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let other_spans = fetch_trace_spans(&pool, &tenant, &other_trace_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if base_spans.is_empty() || other_spans.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let base = build_trace_tree(trace_id, &base_spans);
+    let other = build_trace_tree(other_trace_id, &other_spans);
+
+    Ok(Json(compare_trace_trees(&base, &other)))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AggregateTraceQuery {
+    service_name: String,
+    operation_name: String,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    start: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    end: Option<OffsetDateTime>,
+    limit: Option<i64>, // Maximum number of traces to merge
+}
+
+pub async fn aggregate_traces_handler(
+    State(pool): State<Arc<PgPool>>,
+    Tenant(tenant): Tenant,
+    Query(query): Query<AggregateTraceQuery>,
+) -> Result<Json<AggregateTrace>, StatusCode> {
+    let (start, end) = resolve_time_window(query.start, query.end)?;
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+
+    let trace_ids: Vec<String> = sqlx::query_scalar!(
+        r#"
+        SELECT trace_id
+        FROM span
+        WHERE
+            service_name = $1
+            AND operation_name = $2
+            AND parent_span_id IS NULL
+            AND started_at >= $3
+            AND started_at < $4
+            AND tenant_id = $6
+        GROUP BY trace_id
+        ORDER BY MAX(started_at) DESC
+        LIMIT $5
+        "#,
+        query.service_name,
+        query.operation_name,
+        start,
+        end,
+        limit,
+        tenant,
+    )
+    .fetch_all(&*pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let spans = fetch_spans_for_traces(&pool, &tenant, &trace_ids)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let trees: Vec<TraceTree> = spans
+        .chunk_by(|a, b| a.trace_id == b.trace_id)
+        .map(|trace_spans| build_trace_tree(trace_spans[0].trace_id.clone(), trace_spans))
+        .collect();
+
+    Ok(Json(aggregate_trace_trees(
+        &trees,
+        &query.service_name,
+        &query.operation_name,
+    )))
+}
+
 pub async fn list_span_attributes_handler(
     State(pool): State<Arc<PgPool>>,
+    Tenant(tenant): Tenant,
 ) -> Result<Json<Vec<String>>, StatusCode> {
     let records = sqlx::query!(
         r#"
-        WITH attrs AS (
-            SELECT t.key
-            FROM span, LATERAL JSONB_EACH(attributes) AS t(key, value)
-            WHERE attributes IS NOT NULL
-        )
-
         SELECT DISTINCT key
-        FROM attrs
-        WHERE key IS NOT NULL
-        ORDER BY KEY
+        FROM span_attribute_catalog
+        WHERE tenant_id = $1
+        ORDER BY key
         "#,
+        tenant,
     )
     .fetch_all(&*pool)
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    let attributes: Vec<String> = records
-        .into_iter()
-        .filter_map(|record| record.key)
-        .collect();
+    let attributes: Vec<String> = records.into_iter().map(|record| record.key).collect();
+
+    Ok(Json(attributes))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpanAttributeValuesQuery {
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    start: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    end: Option<OffsetDateTime>,
+    service_name: Option<String>,
+    operation_name: Option<String>,
+    limit: Option<i64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpanAttributeValueCount {
+    pub value: String,
+    pub count: i64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpanAttributeTypeCount {
+    pub value_type: String,
+    pub count: i64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpanAttributeValues {
+    pub key: String,
+    pub span_count: i64,
+    pub distinct_value_count: i64,
+    pub high_cardinality: bool,
+    pub value_types: Vec<SpanAttributeTypeCount>,
+    pub top_values: Vec<SpanAttributeValueCount>,
+}
+
+/// A key is flagged as high-cardinality when it has more distinct values than this in
+/// the window, or when most of a reasonably sized sample has a unique value.
+const HIGH_CARDINALITY_DISTINCT_VALUES: i64 = 1000;
+const HIGH_CARDINALITY_MIN_SPANS: i64 = 100;
+
+pub async fn span_attribute_values_handler(
+    State(pool): State<Arc<PgPool>>,
+    Tenant(tenant): Tenant,
+    Path(key): Path<String>,
+    Query(query): Query<SpanAttributeValuesQuery>,
+) -> Result<Json<SpanAttributeValues>, StatusCode> {
+    let (start, end) = resolve_time_window(query.start, query.end)?;
+    let limit = query.limit.unwrap_or(20).clamp(1, 1000);
+
+    let stats = sqlx::query!(
+        r#"
+        SELECT
+            COUNT(*) AS "span_count!",
+            COUNT(DISTINCT attributes ->> $1) AS "distinct_value_count!"
+        FROM span
+        WHERE
+            started_at >= $2
+            AND started_at < $3
+            AND ($4::TEXT IS NULL OR service_name = $4::TEXT)
+            AND ($5::TEXT IS NULL OR operation_name = $5::TEXT)
+            AND attributes ? $1
+            AND tenant_id = $6
+        "#,
+        key,
+        start,
+        end,
+        query.service_name.as_deref(),
+        query.operation_name.as_deref(),
+        tenant,
+    )
+    .fetch_one(&*pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let value_types = sqlx::query_as!(
+        SpanAttributeTypeCount,
+        r#"
+        SELECT
+            JSONB_TYPEOF(attributes -> $1) AS "value_type!",
+            COUNT(*) AS "count!"
+        FROM span
+        WHERE
+            started_at >= $2
+            AND started_at < $3
+            AND ($4::TEXT IS NULL OR service_name = $4::TEXT)
+            AND ($5::TEXT IS NULL OR operation_name = $5::TEXT)
+            AND attributes ? $1
+            AND tenant_id = $6
+        GROUP BY 1
+        ORDER BY 2 DESC
+        "#,
+        key,
+        start,
+        end,
+        query.service_name.as_deref(),
+        query.operation_name.as_deref(),
+        tenant,
+    )
+    .fetch_all(&*pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let top_values = sqlx::query_as!(
+        SpanAttributeValueCount,
+        r#"
+        SELECT
+            attributes ->> $1 AS "value!",
+            COUNT(*) AS "count!"
+        FROM span
+        WHERE
+            started_at >= $2
+            AND started_at < $3
+            AND ($4::TEXT IS NULL OR service_name = $4::TEXT)
+            AND ($5::TEXT IS NULL OR operation_name = $5::TEXT)
+            AND attributes ? $1
+            AND tenant_id = $7
+        GROUP BY 1
+        ORDER BY 2 DESC, 1
+        LIMIT $6
+        "#,
+        key,
+        start,
+        end,
+        query.service_name.as_deref(),
+        query.operation_name.as_deref(),
+        limit,
+        tenant,
+    )
+    .fetch_all(&*pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let high_cardinality = stats.distinct_value_count > HIGH_CARDINALITY_DISTINCT_VALUES
+        || (stats.span_count >= HIGH_CARDINALITY_MIN_SPANS
+            && stats.distinct_value_count * 2 > stats.span_count);
+
+    Ok(Json(SpanAttributeValues {
+        key,
+        span_count: stats.span_count,
+        distinct_value_count: stats.distinct_value_count,
+        high_cardinality,
+        value_types,
+        top_values,
+    }))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CatalogService {
+    pub service_name: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub first_seen: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub last_seen: OffsetDateTime,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CatalogOperation {
+    pub operation_name: String,
+    pub span_kind: crud::DbSpanKind,
+    #[serde(with = "time::serde::rfc3339")]
+    pub first_seen: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub last_seen: OffsetDateTime,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CatalogAttribute {
+    pub key: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub first_seen: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub last_seen: OffsetDateTime,
+}
+
+pub async fn list_services_handler(
+    State(pool): State<Arc<PgPool>>,
+    Tenant(tenant): Tenant,
+) -> Result<Json<Vec<CatalogService>>, StatusCode> {
+    let services = sqlx::query_as!(
+        CatalogService,
+        r#"
+        SELECT service_name, first_seen, last_seen
+        FROM service_catalog
+        WHERE tenant_id = $1 AND service_name <> ''
+        ORDER BY service_name
+        "#,
+        tenant,
+    )
+    .fetch_all(&*pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(services))
+}
+
+pub async fn list_service_operations_handler(
+    State(pool): State<Arc<PgPool>>,
+    Tenant(tenant): Tenant,
+    Path(service_name): Path<String>,
+) -> Result<Json<Vec<CatalogOperation>>, StatusCode> {
+    let operations = sqlx::query_as!(
+        CatalogOperation,
+        r#"
+        SELECT
+            operation_name,
+            kind AS "span_kind: crud::DbSpanKind",
+            first_seen,
+            last_seen
+        FROM operation_catalog
+        WHERE tenant_id = $1 AND service_name = $2
+        ORDER BY operation_name, kind
+        "#,
+        tenant,
+        service_name,
+    )
+    .fetch_all(&*pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(operations))
+}
+
+pub async fn list_service_attributes_handler(
+    State(pool): State<Arc<PgPool>>,
+    Tenant(tenant): Tenant,
+    Path(service_name): Path<String>,
+) -> Result<Json<Vec<CatalogAttribute>>, StatusCode> {
+    let attributes = sqlx::query_as!(
+        CatalogAttribute,
+        r#"
+        SELECT key, first_seen, last_seen
+        FROM span_attribute_catalog
+        WHERE tenant_id = $1 AND service_name = $2
+        ORDER BY key
+        "#,
+        tenant,
+        service_name,
+    )
+    .fetch_all(&*pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
     Ok(Json(attributes))
 }
@@ -485,68 +1034,199 @@ pub struct QuerySpec {
     filters: Option<Vec<Filter>>,
     group: Option<String>,
     time_bin: Option<TimeBinQuery>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    start: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    end: Option<OffsetDateTime>,
 }
 
-fn time_bin_to_sql(input: &TimeBinQuery) -> String {
-    match input.bin {
-        TimeBin::Second => format!(
-            "DATE_BIN(INTERVAL '{} second', started_at, '1970-01-01 00:00:00'::TIMESTAMPTZ) AS time_bin,",
-            input.value
-        ),
-        TimeBin::Minute => format!(
-            "DATE_BIN(INTERVAL '{} minute', started_at, '1970-01-01 00:00:00'::TIMESTAMPTZ) AS time_bin,",
-            input.value
-        ),
-        TimeBin::Hour => format!(
-            "DATE_BIN(INTERVAL '{} hour', started_at, '1970-01-01 00:00:00'::TIMESTAMPTZ) AS time_bin,",
-            input.value
-        ),
-        TimeBin::Day => format!(
-            "DATE_BIN(INTERVAL '{} day', started_at, '1970-01-01 00:00:00'::TIMESTAMPTZ) AS time_bin,",
-            input.value
-        ),
-    }
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SubQuery {
+    aggregate: Aggregate,
+    filters: Option<Vec<Filter>>,
 }
 
+/// Several named sub-queries combined by an arithmetic `formula` over their names,
+/// e.g. `errors / total * 100`. Sub-queries share the grouping and time binning, and
+/// the formula is evaluated per time bin and group.
 #[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct TimeSeriesValue {
-    #[serde(with = "time::serde::rfc3339")]
-    pub end_time: OffsetDateTime,
-    pub value: f64,
-    pub group: Option<String>,
+pub struct FormulaQuerySpec {
+    queries: HashMap<String, SubQuery>,
+    formula: String,
+    group: Option<String>,
+    time_bin: Option<TimeBinQuery>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    start: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    end: Option<OffsetDateTime>,
 }
 
-fn build_query<'a>(params: &'a QuerySpec) -> QueryBuilder<'a, Postgres> {
-    let allowed_columns = vec![
-        "trace_id",
-        "operation_name",
-        "started_at",
-        "ended_at",
-        "duration_ns",
-        "status_code",
-        "kind",
-        "instrumentation_library",
-        "service_name",
-    ];
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum QueryRequest {
+    Formula(FormulaQuerySpec),
+    Single(QuerySpec),
+}
 
-    let mut builder: QueryBuilder<Postgres> = QueryBuilder::new("SELECT ");
+const DEFAULT_TIME_BIN: TimeBinQuery = TimeBinQuery {
+    bin: TimeBin::Minute,
+    value: 1,
+};
 
-    let time_bin = params.time_bin.as_ref().unwrap_or(&TimeBinQuery {
-        bin: TimeBin::Minute,
-        value: 1,
-    });
-    let time_bin_sql = time_bin_to_sql(time_bin);
+fn time_bin_to_sql(input: &TimeBinQuery, column: &str) -> String {
+    let unit = match input.bin {
+        TimeBin::Second => "second",
+        TimeBin::Minute => "minute",
+        TimeBin::Hour => "hour",
+        TimeBin::Day => "day",
+    };
 
-    builder.push(time_bin_sql.as_str());
+    format!(
+        "DATE_BIN(INTERVAL '{} {}', {}, '1970-01-01 00:00:00'::TIMESTAMPTZ) AS time_bin,",
+        input.value, unit, column
+    )
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TimeSeriesValue {
+    #[serde(with = "time::serde::rfc3339")]
+    pub end_time: OffsetDateTime,
+    pub value: f64,
+    pub group: Option<String>,
+}
+
+const ALLOWED_COLUMNS: [&str; 9] = [
+    "trace_id",
+    "operation_name",
+    "started_at",
+    "ended_at",
+    "duration_ns",
+    "status_code",
+    "kind",
+    "instrumentation_library",
+    "service_name",
+];
+
+/// SQL type of an allowed span column, used to cast the string filter values.
+fn column_type(column: &str) -> &'static str {
+    match column {
+        "started_at" | "ended_at" => "TIMESTAMPTZ",
+        "duration_ns" => "BIGINT",
+        "status_code" => "INTEGER",
+        "kind" => "span_kind",
+        _ => "TEXT",
+    }
+}
+
+fn validate_column(column: &str) -> Result<(), StatusCode> {
+    if ALLOWED_COLUMNS.contains(&column) {
+        Ok(())
+    } else {
+        Err(StatusCode::BAD_REQUEST)
+    }
+}
+
+/// Checks the filter's column, and that its value parses as the column's type so
+/// the `CAST` in `push_filters` can't fail.
+fn validate_filter(filter: &Filter) -> Result<(), StatusCode> {
+    validate_column(&filter.column)?;
+
+    let value = filter.value.as_str();
+    let valid = match column_type(&filter.column) {
+        "TIMESTAMPTZ" => OffsetDateTime::parse(value, &Rfc3339).is_ok(),
+        "BIGINT" => value.parse::<i64>().is_ok(),
+        "INTEGER" => value.parse::<i32>().is_ok(),
+        "span_kind" => crud::DbSpanKind::from_label(value).is_some(),
+        _ => true,
+    };
 
-    if params.group.is_some() {
-        let col = params.group.as_ref().unwrap();
+    if valid {
+        Ok(())
+    } else {
+        Err(StatusCode::BAD_REQUEST)
+    }
+}
+
+fn validate_filters(filters: Option<&Vec<Filter>>) -> Result<(), StatusCode> {
+    filters.into_iter().flatten().try_for_each(validate_filter)
+}
+
+fn validate_time_bin(time_bin: Option<&TimeBinQuery>) -> Result<(), StatusCode> {
+    if time_bin.is_some_and(|time_bin| time_bin.value == 0) {
+        Err(StatusCode::BAD_REQUEST)
+    } else {
+        Ok(())
+    }
+}
+
+/// Checks every column `params` names against `ALLOWED_COLUMNS`, since they are
+/// spliced into the SQL, along with the filter values and time bin.
+fn validate_query(params: &QuerySpec) -> Result<(), StatusCode> {
+    validate_filters(params.filters.as_ref())?;
+    validate_time_bin(params.time_bin.as_ref())?;
 
-        if col.is_empty() || !allowed_columns.contains(&col.as_str()) {
-            panic!("Invalid grouping column: {}", col);
+    if let Some(col) = &params.group {
+        validate_column(col)?;
+    }
+
+    match (&params.aggregate.source, &params.aggregate.agg_type) {
+        (AggregateSource::SpanColumn, AggregateType::Sum(column))
+        | (AggregateSource::SpanColumn, AggregateType::Avg(column))
+        | (AggregateSource::SpanColumn, AggregateType::Min(column))
+        | (AggregateSource::SpanColumn, AggregateType::Max(column)) => validate_column(column),
+        _ => Ok(()),
+    }
+}
+
+fn push_filters(builder: &mut QueryBuilder<'_, Postgres>, filters: &[Filter]) {
+    for (i, filter) in filters.iter().enumerate() {
+        builder.push(format!("{} = CAST(", filter.column));
+        builder.push_bind(filter.value.clone());
+        builder.push(format!(" AS {})", column_type(&filter.column)));
+
+        if i < filters.len() - 1 {
+            builder.push(" AND ");
         }
+    }
+}
 
-        builder.push(&format!("\n{col} AS group, "));
+/// Opens the `WHERE` clause, keeping rows of `tenant` with `column` in `[start, end)`.
+/// Bounds on `span.started_at` let Postgres skip partitions.
+fn push_tenant_and_time_range<'a>(
+    builder: &mut QueryBuilder<'a, Postgres>,
+    tenant: &'a str,
+    column: &str,
+    start: Option<OffsetDateTime>,
+    end: Option<OffsetDateTime>,
+) {
+    builder.push("\nWHERE tenant_id = ");
+    builder.push_bind(tenant);
+
+    for (op, bound) in [(">=", start), ("<", end)] {
+        let Some(bound) = bound else {
+            continue;
+        };
+
+        builder.push(format!(" AND {column} {op} "));
+        builder.push_bind(bound);
+    }
+}
+
+fn build_query<'a>(
+    params: &'a QuerySpec,
+    tenant: &'a str,
+) -> Result<QueryBuilder<'a, Postgres>, StatusCode> {
+    validate_query(params)?;
+
+    let mut builder: QueryBuilder<Postgres> = QueryBuilder::new("SELECT ");
+
+    let time_bin = params.time_bin.as_ref().unwrap_or(&DEFAULT_TIME_BIN);
+    let time_bin_sql = time_bin_to_sql(time_bin, "started_at");
+
+    builder.push(time_bin_sql.as_str());
+
+    if let Some(col) = &params.group {
+        builder.push(format!("\n{col}::TEXT AS group, "));
     }
 
     match &params.aggregate.source {
@@ -555,29 +1235,16 @@ fn build_query<'a>(params: &'a QuerySpec) -> QueryBuilder<'a, Postgres> {
                 builder.push("\nCOUNT(*)::DOUBLE PRECISION AS value");
             }
             AggregateType::Sum(column) => {
-                if column.is_empty() || !allowed_columns.contains(&column.as_str()) {
-                    panic!("Invalid aggregate column: {}", column);
-                }
-
-                builder.push(&format!("\nSUM({})::DOUBLE PRECISION AS value", column));
+                builder.push(format!("\nSUM({})::DOUBLE PRECISION AS value", column));
             }
             AggregateType::Avg(column) => {
-                if column.is_empty() || !allowed_columns.contains(&column.as_str()) {
-                    panic!("Invalid aggregate column: {}", column);
-                }
-                builder.push(&format!("\nAVG({})::DOUBLE PRECISION AS value", column));
+                builder.push(format!("\nAVG({})::DOUBLE PRECISION AS value", column));
             }
             AggregateType::Min(column) => {
-                if column.is_empty() || !allowed_columns.contains(&column.as_str()) {
-                    panic!("Invalid aggregate column: {}", column);
-                }
-                builder.push(&format!("\nMIN({})::DOUBLE PRECISION AS value", column));
+                builder.push(format!("\nMIN({})::DOUBLE PRECISION AS value", column));
             }
             AggregateType::Max(column) => {
-                if column.is_empty() || !allowed_columns.contains(&column.as_str()) {
-                    panic!("Invalid aggregate column: {}", column);
-                }
-                builder.push(&format!("\nMAX({})::DOUBLE PRECISION AS value", column));
+                builder.push(format!("\nMAX({})::DOUBLE PRECISION AS value", column));
             }
         },
         AggregateSource::SpanAttribute => {
@@ -611,37 +1278,15 @@ fn build_query<'a>(params: &'a QuerySpec) -> QueryBuilder<'a, Postgres> {
 
     builder.push("\nFROM span ");
 
-    let mut has_where_clause = false;
-
-    if let Some(filters) = &params.filters {
-        if !filters.is_empty() {
-            builder.push("\nWHERE ");
-            has_where_clause = true;
+    push_tenant_and_time_range(&mut builder, tenant, "started_at", params.start, params.end);
 
-            for (i, filter) in filters.iter().enumerate() {
-                if filter.column.is_empty() || !allowed_columns.contains(&filter.column.as_str()) {
-                    panic!("Invalid filter column: {}", filter.column);
-                }
-
-                builder.push(&format!("{} = ", filter.column));
-                builder.push_bind(filter.value.as_str());
-
-                if i < filters.len() - 1 {
-                    builder.push(" AND ");
-                }
-            }
-        }
+    if let Some(filters) = params.filters.as_ref().filter(|f| !f.is_empty()) {
+        builder.push(" AND ");
+        push_filters(&mut builder, filters);
     }
 
-    if &params.aggregate.source == &AggregateSource::SpanAttribute {
-        if !has_where_clause {
-            builder.push("\nWHERE ");
-            has_where_clause = true;
-        } else {
-            builder.push(" AND ");
-        }
-
-        builder.push("attributes IS NOT NULL");
+    if params.aggregate.source == AggregateSource::SpanAttribute {
+        builder.push(" AND attributes IS NOT NULL");
 
         match &params.aggregate.agg_type {
             AggregateType::Sum(key)
@@ -650,7 +1295,7 @@ fn build_query<'a>(params: &'a QuerySpec) -> QueryBuilder<'a, Postgres> {
             | AggregateType::Max(key) => {
                 builder.push(" AND attributes ? ");
                 builder.push_bind(key.as_str());
-                builder.push("AND jsonb_typeof(attributes -> ");
+                builder.push(" AND jsonb_typeof(attributes -> ");
                 builder.push_bind(key.as_str());
                 builder.push(") = 'number'");
             }
@@ -660,40 +1305,34 @@ fn build_query<'a>(params: &'a QuerySpec) -> QueryBuilder<'a, Postgres> {
 
     builder.push("\nGROUP BY time_bin");
 
-    if params.group.is_some() {
-        let col = params.group.as_ref().unwrap();
-
-        if col.is_empty() || !allowed_columns.contains(&col.as_str()) {
-            panic!("Invalid grouping column: {}", col);
-        }
-
-        builder.push(&format!(", {}", col));
+    if let Some(col) = &params.group {
+        builder.push(format!(", {}", col));
     }
 
     builder.push("\nORDER BY time_bin");
 
-    if params.group.is_some() {
-        let col = params.group.as_ref().unwrap();
-
-        if col.is_empty() || !allowed_columns.contains(&col.as_str()) {
-            panic!("Invalid ordering column: {}", col);
-        }
-
-        builder.push(&format!(", {}", col));
+    if let Some(col) = &params.group {
+        builder.push(format!(", {}", col));
     }
 
-    builder
+    Ok(builder)
 }
 
-pub async fn query_handler(
-    State(pool): State<Arc<PgPool>>,
-    Json(query_spec): Json<QuerySpec>,
-) -> Result<Json<Vec<TimeSeriesValue>>, StatusCode> {
-    let mut builder = build_query(&query_spec);
+async fn run_query(
+    pool: &PgPool,
+    tenant: &str,
+    query_spec: &QuerySpec,
+) -> Result<Vec<TimeSeriesValue>, StatusCode> {
+    validate_query(query_spec)?;
+
+    let mut builder = match rollup::build_rollup_query(query_spec, tenant) {
+        Some(builder) => builder,
+        None => build_query(query_spec, tenant)?,
+    };
     let query = builder.build();
 
     let results = query
-        .fetch_all(&*pool)
+        .fetch_all(pool)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
@@ -715,30 +1354,739 @@ pub async fn query_handler(
         });
     }
 
+    Ok(time_series_values)
+}
+
+async fn run_formula_query(
+    pool: &PgPool,
+    tenant: &str,
+    formula_spec: &FormulaQuerySpec,
+) -> Result<Vec<TimeSeriesValue>, StatusCode> {
+    let expr = formula::parse_for_queries(&formula_spec.formula, &formula_spec.queries)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let mut results = Vec::new();
+
+    for (name, sub_query) in &formula_spec.queries {
+        let query_spec = QuerySpec {
+            aggregate: sub_query.aggregate.clone(),
+            filters: sub_query.filters.clone(),
+            group: formula_spec.group.clone(),
+            time_bin: formula_spec.time_bin.clone(),
+            start: formula_spec.start,
+            end: formula_spec.end,
+        };
+
+        let values = run_query(pool, tenant, &query_spec).await?;
+
+        results.push((name.clone(), values));
+    }
+
+    Ok(formula::combine(&expr, results))
+}
+
+pub async fn query_handler(
+    State(pool): State<Arc<PgPool>>,
+    Tenant(tenant): Tenant,
+    Json(query_request): Json<QueryRequest>,
+) -> Result<Json<Vec<TimeSeriesValue>>, StatusCode> {
+    let time_series_values = match &query_request {
+        QueryRequest::Formula(formula_spec) => {
+            run_formula_query(&pool, &tenant, formula_spec).await?
+        }
+        QueryRequest::Single(query_spec) => run_query(&pool, &tenant, query_spec).await?,
+    };
+
     Ok(Json(time_series_values))
 }
 
+/// Latency distribution over `span.duration_ns`, counted per time bin in log2-scaled
+/// buckets: bucket `n` covers durations in `[2^n, 2^(n+1))` nanoseconds.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HeatmapSpec {
+    filters: Option<Vec<Filter>>,
+    time_bin: Option<TimeBinQuery>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    start: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    end: Option<OffsetDateTime>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HeatmapBucket {
+    pub lower_bound_ns: i64,
+    pub upper_bound_ns: i64,
+    pub count: i64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HeatmapBin {
+    #[serde(with = "time::serde::rfc3339")]
+    pub end_time: OffsetDateTime,
+    pub buckets: Vec<HeatmapBucket>,
+}
+
+fn validate_heatmap_query(params: &HeatmapSpec) -> Result<(), StatusCode> {
+    validate_filters(params.filters.as_ref())?;
+    validate_time_bin(params.time_bin.as_ref())
+}
+
+fn build_heatmap_query<'a>(
+    params: &'a HeatmapSpec,
+    tenant: &'a str,
+) -> Result<QueryBuilder<'a, Postgres>, StatusCode> {
+    validate_heatmap_query(params)?;
+
+    let mut builder: QueryBuilder<Postgres> = QueryBuilder::new("SELECT ");
+
+    let time_bin = params.time_bin.as_ref().unwrap_or(&DEFAULT_TIME_BIN);
+    builder.push(time_bin_to_sql(time_bin, "started_at").as_str());
+
+    builder.push(
+        "\nFLOOR(LOG(2, GREATEST(duration_ns, 1)::NUMERIC))::INTEGER AS bucket,\nCOUNT(*) AS count",
+    );
+    builder.push("\nFROM span ");
+
+    push_tenant_and_time_range(&mut builder, tenant, "started_at", params.start, params.end);
+
+    if let Some(filters) = params.filters.as_ref().filter(|f| !f.is_empty()) {
+        builder.push(" AND ");
+        push_filters(&mut builder, filters);
+    }
+
+    builder.push("\nGROUP BY time_bin, bucket");
+    builder.push("\nORDER BY time_bin, bucket");
+
+    Ok(builder)
+}
+
+pub async fn heatmap_query_handler(
+    State(pool): State<Arc<PgPool>>,
+    Tenant(tenant): Tenant,
+    Json(heatmap_spec): Json<HeatmapSpec>,
+) -> Result<Json<Vec<HeatmapBin>>, StatusCode> {
+    validate_heatmap_query(&heatmap_spec)?;
+
+    let mut builder = match rollup::build_rollup_heatmap_query(&heatmap_spec, &tenant) {
+        Some(builder) => builder,
+        None => build_heatmap_query(&heatmap_spec, &tenant)?,
+    };
+    let query = builder.build();
+
+    let results = query
+        .fetch_all(&*pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut bins: Vec<HeatmapBin> = Vec::new();
+
+    for r in results {
+        let time_bin = r.get::<OffsetDateTime, _>("time_bin");
+        let bucket: i32 = r.get("bucket");
+        let count: i64 = r.get("count");
+
+        let heatmap_bucket = HeatmapBucket {
+            lower_bound_ns: 1_i64 << bucket,
+            upper_bound_ns: if bucket >= 62 {
+                i64::MAX
+            } else {
+                1_i64 << (bucket + 1)
+            },
+            count,
+        };
+
+        match bins.last_mut() {
+            Some(bin) if bin.end_time == time_bin => bin.buckets.push(heatmap_bucket),
+            _ => bins.push(HeatmapBin {
+                end_time: time_bin,
+                buckets: vec![heatmap_bucket],
+            }),
+        }
+    }
+
+    Ok(Json(bins))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RedMetricsQuery {
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    start: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    end: Option<OffsetDateTime>,
+    service_name: Option<String>,
+    kinds: Option<String>, // Comma-separated span kinds, defaults to SERVER,CONSUMER
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RedMetrics {
+    pub service_name: Option<String>,
+    pub operation_name: String,
+    pub request_count: i64,
+    pub error_count: i64,
+    pub rate_per_second: f64,
+    pub error_rate: f64,
+    pub p50_duration_ns: Option<f64>,
+    pub p90_duration_ns: Option<f64>,
+    pub p99_duration_ns: Option<f64>,
+}
+
+/// Resolves an optional `[start, end)` window, defaulting to the hour before `end`
+/// (itself defaulting to now).
+fn resolve_time_window(
+    start: Option<OffsetDateTime>,
+    end: Option<OffsetDateTime>,
+) -> Result<(OffsetDateTime, OffsetDateTime), StatusCode> {
+    let end = end.unwrap_or_else(OffsetDateTime::now_utc);
+    let start = start.unwrap_or(end - time::Duration::hours(1));
+
+    if start >= end {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    Ok((start, end))
+}
+
+fn parse_span_kinds(kinds: Option<&str>) -> Result<Vec<String>, StatusCode> {
+    let Some(kinds) = kinds else {
+        return Ok(vec!["SERVER".to_string(), "CONSUMER".to_string()]);
+    };
+
+    kinds
+        .split(',')
+        .map(|kind| {
+            let kind = kind.trim().to_uppercase();
+            match kind.as_str() {
+                "UNSPECIFIED" | "INTERNAL" | "SERVER" | "CLIENT" | "PRODUCER" | "CONSUMER" => {
+                    Ok(kind)
+                }
+                _ => Err(StatusCode::BAD_REQUEST),
+            }
+        })
+        .collect()
+}
+
+pub async fn red_metrics_handler(
+    State(pool): State<Arc<PgPool>>,
+    Tenant(tenant): Tenant,
+    Query(query): Query<RedMetricsQuery>,
+) -> Result<Json<Vec<RedMetrics>>, StatusCode> {
+    let (start, end) = resolve_time_window(query.start, query.end)?;
+    let kinds = parse_span_kinds(query.kinds.as_deref())?;
+
+    let records = sqlx::query!(
+        r#"
+        SELECT
+            service_name,
+            operation_name,
+            COUNT(*) AS "request_count!",
+            COUNT(*) FILTER (WHERE status_code = 2) AS "error_count!",
+            PERCENTILE_CONT(0.5) WITHIN GROUP (ORDER BY duration_ns) AS p50_duration_ns,
+            PERCENTILE_CONT(0.9) WITHIN GROUP (ORDER BY duration_ns) AS p90_duration_ns,
+            PERCENTILE_CONT(0.99) WITHIN GROUP (ORDER BY duration_ns) AS p99_duration_ns
+        FROM span
+        WHERE
+            started_at >= $1
+            AND started_at < $2
+            AND ($3::TEXT IS NULL OR service_name = $3::TEXT)
+            AND kind::TEXT = ANY($4::TEXT[])
+            AND tenant_id = $5
+        GROUP BY service_name, operation_name
+        ORDER BY service_name, operation_name
+        "#,
+        start,
+        end,
+        query.service_name.as_deref(),
+        &kinds,
+        tenant,
+    )
+    .fetch_all(&*pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let window_seconds = (end - start).as_seconds_f64();
+
+    let metrics: Vec<RedMetrics> = records
+        .into_iter()
+        .map(|record| RedMetrics {
+            service_name: record.service_name,
+            operation_name: record.operation_name,
+            request_count: record.request_count,
+            error_count: record.error_count,
+            rate_per_second: record.request_count as f64 / window_seconds,
+            error_rate: record.error_count as f64 / record.request_count as f64,
+            p50_duration_ns: record.p50_duration_ns,
+            p90_duration_ns: record.p90_duration_ns,
+            p99_duration_ns: record.p99_duration_ns,
+        })
+        .collect();
+
+    Ok(Json(metrics))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TimeWindowQuery {
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    start: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    end: Option<OffsetDateTime>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ServiceGraphNode {
+    pub service_name: Option<String>,
+    pub span_count: i64,
+    pub error_count: i64,
+}
+
+/// Calls from spans of `source` to child spans of `target`; error counts and latencies
+/// are taken from the callee side.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ServiceGraphEdge {
+    pub source: Option<String>,
+    pub target: Option<String>,
+    pub call_count: i64,
+    pub error_count: i64,
+    pub avg_duration_ns: Option<f64>,
+    pub p50_duration_ns: Option<f64>,
+    pub p95_duration_ns: Option<f64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ServiceGraph {
+    pub nodes: Vec<ServiceGraphNode>,
+    pub edges: Vec<ServiceGraphEdge>,
+}
+
+pub async fn service_graph_handler(
+    State(pool): State<Arc<PgPool>>,
+    Tenant(tenant): Tenant,
+    Query(query): Query<TimeWindowQuery>,
+) -> Result<Json<ServiceGraph>, StatusCode> {
+    let (start, end) = resolve_time_window(query.start, query.end)?;
+
+    let node_records = sqlx::query!(
+        r#"
+        SELECT
+            service_name,
+            COUNT(*) AS "span_count!",
+            COUNT(*) FILTER (WHERE status_code = 2) AS "error_count!"
+        FROM span
+        WHERE tenant_id = $3 AND started_at >= $1 AND started_at < $2
+        GROUP BY service_name
+        ORDER BY service_name
+        "#,
+        start,
+        end,
+        tenant,
+    )
+    .fetch_all(&*pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let edge_records = sqlx::query!(
+        r#"
+        SELECT
+            parent.service_name AS source,
+            child.service_name AS target,
+            COUNT(*) AS "call_count!",
+            COUNT(*) FILTER (WHERE child.status_code = 2) AS "error_count!",
+            AVG(child.duration_ns)::DOUBLE PRECISION AS avg_duration_ns,
+            PERCENTILE_CONT(0.5) WITHIN GROUP (ORDER BY child.duration_ns) AS p50_duration_ns,
+            PERCENTILE_CONT(0.95) WITHIN GROUP (ORDER BY child.duration_ns) AS p95_duration_ns
+        FROM span child
+        JOIN span parent
+            ON parent.tenant_id = child.tenant_id
+            AND parent.trace_id = child.trace_id
+            AND parent.id = child.parent_span_id
+        WHERE
+            child.tenant_id = $3
+            AND child.started_at >= $1
+            AND child.started_at < $2
+            -- Parents are looked up within a day of the window (allowing for clock skew
+            -- and long-running parents) so only nearby partitions are scanned.
+            AND parent.started_at >= $1 - INTERVAL '1 day'
+            AND parent.started_at < $2 + INTERVAL '1 day'
+            AND parent.service_name IS DISTINCT FROM child.service_name
+        GROUP BY parent.service_name, child.service_name
+        ORDER BY parent.service_name, child.service_name
+        "#,
+        start,
+        end,
+        tenant,
+    )
+    .fetch_all(&*pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let nodes = node_records
+        .into_iter()
+        .map(|record| ServiceGraphNode {
+            service_name: record.service_name,
+            span_count: record.span_count,
+            error_count: record.error_count,
+        })
+        .collect();
+
+    let edges = edge_records
+        .into_iter()
+        .map(|record| ServiceGraphEdge {
+            source: record.source,
+            target: record.target,
+            call_count: record.call_count,
+            error_count: record.error_count,
+            avg_duration_ns: record.avg_duration_ns,
+            p50_duration_ns: record.p50_duration_ns,
+            p95_duration_ns: record.p95_duration_ns,
+        })
+        .collect();
+
+    Ok(Json(ServiceGraph { nodes, edges }))
+}
+
 async fn health_check() -> &'static str {
     "OK"
 }
 
-pub fn create_otel_router(pool: Arc<PgPool>) -> Router {
+pub async fn ingest_stats_handler(State(state): State<ApiState>) -> Json<Vec<IngestStats>> {
+    Json(state.head_sampler.stats())
+}
+
+/// Depth of the on-disk spool, or 404 when spooling is disabled.
+pub async fn ingest_spool_handler(
+    State(state): State<ApiState>,
+) -> Result<Json<SpoolStats>, StatusCode> {
+    state
+        .ingest_queue
+        .spool_stats()
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Spans and logs dropped because the database rejected them.
+pub async fn ingest_rejected_handler(State(state): State<ApiState>) -> Json<RejectedStats> {
+    Json(state.ingest_queue.rejected_stats())
+}
+
+/// Corrections and rejections made by span validation, per service.
+pub async fn ingest_validation_handler(
+    State(state): State<ApiState>,
+) -> Json<Vec<ValidationStats>> {
+    Json(state.span_validator.stats())
+}
+
+/// Rejects exports without a key with the `ingest` scope, while auth is enabled.
+async fn require_ingest_key(
+    State(state): State<OtelState>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, OtlpError> {
+    let key = state
+        .auth
+        .authorize(&state.pool, request.headers(), Scope::Ingest)
+        .await
+        .map_err(|e| match e {
+            AuthError::Unauthenticated => OtlpError::unauthenticated(e.to_string()),
+            AuthError::Forbidden(_) => OtlpError::permission_denied(e.to_string()),
+            AuthError::Database(e) => {
+                tracing::error!("Checking API key failed: {}", e);
+                OtlpError::unavailable(state.ingest_queue.retry_after())
+            }
+            AuthError::Hashing(_) => {
+                tracing::error!("Checking API key failed: {}", e);
+                OtlpError::internal("Checking API key failed")
+            }
+        })?;
+
+    if let Some(key) = key {
+        request.extensions_mut().insert(key);
+    }
+
+    Ok(next.run(request).await)
+}
+
+/// Rejects requests without a key with `scope`, while auth is enabled.
+async fn require_api_key(
+    State((state, scope)): State<(ApiState, Scope)>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let key = state
+        .auth
+        .authorize(&state.pool, request.headers(), scope)
+        .await
+        .map_err(|e| match e {
+            AuthError::Unauthenticated => StatusCode::UNAUTHORIZED,
+            AuthError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AuthError::Database(_) | AuthError::Hashing(_) => {
+                tracing::error!("Checking API key failed: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+    if let Some(key) = key {
+        request.extensions_mut().insert(key);
+    }
+
+    Ok(next.run(request).await)
+}
+
+/// Keeps keys bound to a tenant out of the admin endpoints, which span every tenant: a
+/// bound admin key could otherwise create an unbound one.
+async fn require_unbound_key(request: Request, next: Next) -> Result<Response, StatusCode> {
+    let bound = request
+        .extensions()
+        .get::<AuthenticatedKey>()
+        .is_some_and(|key| key.tenant_id.is_some());
+    if bound {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    Ok(next.run(request).await)
+}
+
+/// Hides key management while auth is disabled, since anyone could create keys then.
+async fn require_auth_enabled(
+    State(state): State<ApiState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    if !state.auth.is_enabled() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    Ok(next.run(request).await)
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// Binds the key to one tenant. Unbound keys pick the tenant per request.
+    pub tenant_id: Option<String>,
+}
+
+/// Creates a key, returning it in full this once.
+pub async fn create_api_key_handler(
+    State(state): State<ApiState>,
+    Json(request): Json<CreateApiKeyRequest>,
+) -> Result<(StatusCode, Json<CreatedApiKey>), StatusCode> {
+    if request.name.is_empty() || request.scopes.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    if let Some(tenant_id) = &request.tenant_id {
+        validate_tenant(tenant_id).map_err(|_| StatusCode::BAD_REQUEST)?;
+    }
+
+    let created = state
+        .auth
+        .create_key(
+            &state.pool,
+            &request.name,
+            &request.scopes,
+            request.tenant_id.as_deref(),
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!("Creating API key failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+pub async fn list_api_keys_handler(
+    State(state): State<ApiState>,
+) -> Result<Json<Vec<ApiKey>>, StatusCode> {
+    state
+        .auth
+        .list_keys(&state.pool)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+pub async fn revoke_api_key_handler(
+    State(state): State<ApiState>,
+    Path(id): Path<uuid::Uuid>,
+) -> Result<StatusCode, StatusCode> {
+    let revoked = state
+        .auth
+        .revoke_key(&state.pool, id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if revoked {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
+}
+
+pub fn create_otel_router(state: OtelState) -> Router {
     Router::new()
-        .route("/health", get(health_check))
         .route("/v1/traces", post(insert_traces_handler))
         .route("/v1/logs", post(insert_logs_handler))
-        .with_state(pool)
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            require_ingest_key,
+        ))
+        .route("/health", get(health_check))
+        .with_state(state)
 }
 
-pub fn create_api_router(pool: Arc<PgPool>) -> Router {
+pub fn create_api_router(state: ApiState) -> Router {
+    // Keys and ingest stats cover every tenant, so they're for admin keys without one.
+    let admin = Router::new()
+        .route(
+            "/api-keys",
+            get(list_api_keys_handler).post(create_api_key_handler),
+        )
+        .route("/api-keys/{id}", delete(revoke_api_key_handler))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            require_auth_enabled,
+        ))
+        .route("/ingest/stats", get(ingest_stats_handler))
+        .route("/ingest/spool", get(ingest_spool_handler))
+        .route("/ingest/rejected", get(ingest_rejected_handler))
+        .route("/ingest/validation", get(ingest_validation_handler))
+        .route_layer(middleware::from_fn(require_unbound_key))
+        .route_layer(middleware::from_fn_with_state(
+            (state.clone(), Scope::Admin),
+            require_api_key,
+        ));
+
     Router::new()
-        .route("/health", get(health_check))
         .route("/traces", get(search_traces_handler))
+        .route("/traces/aggregate", get(aggregate_traces_handler))
         .route("/traces/{trace_id}", get(get_trace_handler))
         .route("/traces/{trace_id}/spans", get(get_trace_spans_handler))
+        .route("/traces/{trace_id}/tree", get(get_trace_tree_handler))
+        .route(
+            "/traces/{trace_id}/compare/{other_trace_id}",
+            get(compare_traces_handler),
+        )
         .route("/spans", get(list_spans_handler))
         .route("/logs", get(list_logs_handler))
         .route("/span-attributes", get(list_span_attributes_handler))
+        .route(
+            "/span-attributes/{key}/values",
+            get(span_attribute_values_handler),
+        )
+        .route("/services", get(list_services_handler))
+        .route(
+            "/services/{service_name}/operations",
+            get(list_service_operations_handler),
+        )
+        .route(
+            "/services/{service_name}/attributes",
+            get(list_service_attributes_handler),
+        )
         .route("/query", post(query_handler))
-        .with_state(pool)
+        .route("/query/heatmap", post(heatmap_query_handler))
+        .route("/red-metrics", get(red_metrics_handler))
+        .route("/service-graph", get(service_graph_handler))
+        .route_layer(middleware::from_fn_with_state(
+            (state.clone(), Scope::Read),
+            require_api_key,
+        ))
+        .merge(admin)
+        .route("/health", get(health_check))
+        .with_state(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(spec: serde_json::Value) -> QuerySpec {
+        serde_json::from_value(spec).unwrap()
+    }
+
+    async fn status_with_key(key: Option<AuthenticatedKey>) -> StatusCode {
+        use tower::Service;
+
+        let mut router = Router::new()
+            .route("/api-keys", get(|| async { "keys" }))
+            .route_layer(middleware::from_fn(require_unbound_key));
+
+        let mut request = Request::get("/api-keys")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        if let Some(key) = key {
+            request.extensions_mut().insert(key);
+        }
+
+        router.call(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn admin_endpoints_reject_bound_admin_keys() {
+        let key = |tenant_id: Option<&str>| AuthenticatedKey {
+            id: None,
+            scopes: vec![Scope::Admin],
+            tenant_id: tenant_id.map(str::to_string),
+        };
+
+        assert_eq!(
+            status_with_key(Some(key(Some("acme")))).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(status_with_key(Some(key(None))).await, StatusCode::OK);
+        // Without auth there's no key at all.
+        assert_eq!(status_with_key(None).await, StatusCode::OK);
+    }
+
+    #[test]
+    fn groups_are_read_back_as_text() {
+        let spec = query(serde_json::json!({
+            "aggregate": { "agg_type": "Count", "source": "SpanColumn" },
+            "group": "status_code",
+        }));
+
+        let builder = build_query(&spec, "default").unwrap();
+        let sql = builder.sql();
+
+        assert!(sql.contains("status_code::TEXT AS group"));
+        assert!(sql.contains("GROUP BY time_bin, status_code"));
+    }
+
+    #[test]
+    fn filter_values_must_parse_as_the_column_type() {
+        let filter = |column: &str, value: &str| Filter {
+            column: column.to_string(),
+            value: value.to_string(),
+        };
+
+        assert!(validate_filter(&filter("status_code", "2")).is_ok());
+        assert!(validate_filter(&filter("duration_ns", "1500000")).is_ok());
+        assert!(validate_filter(&filter("kind", "SERVER")).is_ok());
+        assert!(validate_filter(&filter("started_at", "2026-10-18T12:00:00Z")).is_ok());
+        assert!(validate_filter(&filter("service_name", "abc")).is_ok());
+
+        for (column, value) in [
+            ("status_code", "abc"),
+            ("status_code", "99999999999"),
+            ("duration_ns", "1.5"),
+            ("kind", "server"),
+            ("started_at", "yesterday"),
+        ] {
+            assert_eq!(
+                validate_filter(&filter(column, value)),
+                Err(StatusCode::BAD_REQUEST),
+                "{column} = {value}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_zero_time_bins() {
+        let spec = query(serde_json::json!({
+            "aggregate": { "agg_type": "Count", "source": "SpanColumn" },
+            "time_bin": { "bin": "Second", "value": 0 },
+        }));
+
+        assert_eq!(validate_query(&spec), Err(StatusCode::BAD_REQUEST));
+    }
 }
@@ -0,0 +1,384 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::http::{HeaderMap, header};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use time::OffsetDateTime;
+
+/// Keys look like `stl_` followed by 64 hex characters, of which the first 8 are
+/// stored unhashed to find the key.
+const KEY_PREFIX: &str = "stl_";
+const LOOKUP_LENGTH: usize = KEY_PREFIX.len() + 8;
+/// New keys are regenerated this many times when their prefix is already taken.
+const MAX_CREATE_ATTEMPTS: usize = 5;
+
+#[derive(Clone, Debug)]
+pub struct AuthConfig {
+    /// Requests need an API key when set. Off by default so existing setups keep working.
+    pub enabled: bool,
+    /// A key with every scope, for creating the first keys.
+    pub admin_key: Option<String>,
+    /// How long a verified key is trusted before it's checked against the database
+    /// again, which bounds how long a revoked key keeps working on other servers.
+    pub cache_ttl: Duration,
+}
+
+impl AuthConfig {
+    pub fn from_env() -> Self {
+        let enabled = std::env::var("AUTH_ENABLED")
+            .map(|v| v.parse().expect("AUTH_ENABLED must be true or false"))
+            .unwrap_or(false);
+        let admin_key = std::env::var("AUTH_ADMIN_KEY").ok();
+        let cache_ttl_secs = std::env::var("AUTH_CACHE_TTL_SECS")
+            .map(|v| {
+                v.parse()
+                    .expect("AUTH_CACHE_TTL_SECS must be a valid number")
+            })
+            .unwrap_or(60);
+
+        AuthConfig {
+            enabled,
+            admin_key,
+            cache_ttl: Duration::from_secs(cache_ttl_secs),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Sending traces and logs.
+    Ingest,
+    /// Every query endpoint.
+    Read,
+    /// Managing API keys. Implies the other scopes.
+    Admin,
+}
+
+impl Scope {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Ingest => "ingest",
+            Scope::Read => "read",
+            Scope::Admin => "admin",
+        }
+    }
+
+    fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "ingest" => Some(Scope::Ingest),
+            "read" => Some(Scope::Read),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// The key a request was made with, added to the request's extensions.
+#[derive(Clone, Debug)]
+pub struct AuthenticatedKey {
+    /// `None` for `AUTH_ADMIN_KEY`.
+    pub id: Option<uuid::Uuid>,
+    pub scopes: Vec<Scope>,
+    /// The only tenant the key can act for, or `None` to pick one per request.
+    pub tenant_id: Option<String>,
+}
+
+impl AuthenticatedKey {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope) || self.scopes.contains(&Scope::Admin)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: uuid::Uuid,
+    pub name: String,
+    /// The start of the key, to tell keys apart.
+    pub prefix: String,
+    pub scopes: Vec<Scope>,
+    pub tenant_id: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_used_at: Option<OffsetDateTime>,
+}
+
+/// A newly created key, the only time the key itself is returned.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+    pub key: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("missing or invalid API key")]
+    Unauthenticated,
+    #[error("API key lacks the {0:?} scope")]
+    Forbidden(Scope),
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+    #[error("hashing API key failed: {0}")]
+    Hashing(String),
+}
+
+fn digest(key: &str) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+fn parse_scopes(scopes: &[String]) -> Vec<Scope> {
+    scopes.iter().filter_map(|s| Scope::parse(s)).collect()
+}
+
+/// Reads the key from `Authorization: Bearer <key>` or `X-API-Key`.
+fn request_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .or_else(|| headers.get("x-api-key").and_then(|v| v.to_str().ok()))
+        .map(str::trim)
+}
+
+struct CachedKey {
+    key: AuthenticatedKey,
+    expires_at: Instant,
+}
+
+/// Checks API keys against the database, caching verified keys by their SHA-256 since
+/// bcrypt is deliberately too slow to run on every export.
+pub struct Auth {
+    config: AuthConfig,
+    admin_key_digest: Option<[u8; 32]>,
+    cache: Mutex<HashMap<[u8; 32], CachedKey>>,
+}
+
+impl Auth {
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    pub fn new(config: AuthConfig) -> Self {
+        if config.enabled && config.admin_key.is_none() {
+            tracing::warn!("AUTH_ENABLED is set without AUTH_ADMIN_KEY, so no keys can be created");
+        }
+
+        Auth {
+            admin_key_digest: config.admin_key.as_deref().map(digest),
+            config,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the request's key if it has `scope`. Always succeeds, with no key, while
+    /// auth is disabled.
+    pub async fn authorize(
+        &self,
+        pool: &PgPool,
+        headers: &HeaderMap,
+        scope: Scope,
+    ) -> Result<Option<AuthenticatedKey>, AuthError> {
+        if !self.config.enabled {
+            return Ok(None);
+        }
+
+        let key = request_key(headers).ok_or(AuthError::Unauthenticated)?;
+        let key = self.verify(pool, key).await?;
+        if !key.allows(scope) {
+            return Err(AuthError::Forbidden(scope));
+        }
+
+        Ok(Some(key))
+    }
+
+    async fn verify(&self, pool: &PgPool, key: &str) -> Result<AuthenticatedKey, AuthError> {
+        let key_digest = digest(key);
+        if Some(key_digest) == self.admin_key_digest {
+            return Ok(AuthenticatedKey {
+                id: None,
+                scopes: vec![Scope::Admin],
+                tenant_id: None,
+            });
+        }
+
+        if let Some(cached) = self.cache.lock().unwrap().get(&key_digest)
+            && cached.expires_at > Instant::now()
+        {
+            return Ok(cached.key.clone());
+        }
+
+        if !key.starts_with(KEY_PREFIX) || key.len() < LOOKUP_LENGTH {
+            return Err(AuthError::Unauthenticated);
+        }
+
+        let row = sqlx::query!(
+            r#"
+            SELECT id, key_hash, scopes, tenant_id
+            FROM api_key
+            WHERE prefix = $1 AND revoked_at IS NULL
+            "#,
+            &key[..LOOKUP_LENGTH]
+        )
+        .fetch_optional(pool)
+        .await?
+        .ok_or(AuthError::Unauthenticated)?;
+
+        let key = key.to_string();
+        let key_hash = row.key_hash;
+        let verified = tokio::task::spawn_blocking(move || bcrypt::verify(key, &key_hash))
+            .await
+            .map_err(|e| AuthError::Hashing(e.to_string()))?
+            .unwrap_or(false);
+        if !verified {
+            return Err(AuthError::Unauthenticated);
+        }
+
+        sqlx::query!(
+            "UPDATE api_key SET last_used_at = NOW() WHERE id = $1",
+            row.id
+        )
+        .execute(pool)
+        .await?;
+
+        let key = AuthenticatedKey {
+            id: Some(row.id),
+            scopes: parse_scopes(&row.scopes),
+            tenant_id: row.tenant_id,
+        };
+        let mut cache = self.cache.lock().unwrap();
+        let now = Instant::now();
+        cache.retain(|_, cached| cached.expires_at > now);
+        cache.insert(
+            key_digest,
+            CachedKey {
+                key: key.clone(),
+                expires_at: now + self.config.cache_ttl,
+            },
+        );
+
+        Ok(key)
+    }
+
+    /// Creates a key, generating a new one when its prefix collides with an existing key.
+    pub async fn create_key(
+        &self,
+        pool: &PgPool,
+        name: &str,
+        scopes: &[Scope],
+        tenant_id: Option<&str>,
+    ) -> Result<CreatedApiKey, AuthError> {
+        let mut attempt = 1;
+
+        loop {
+            match self.try_create_key(pool, name, scopes, tenant_id).await {
+                Err(AuthError::Database(e))
+                    if attempt < MAX_CREATE_ATTEMPTS
+                        && e.as_database_error()
+                            .is_some_and(|e| e.is_unique_violation()) =>
+                {
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn try_create_key(
+        &self,
+        pool: &PgPool,
+        name: &str,
+        scopes: &[Scope],
+        tenant_id: Option<&str>,
+    ) -> Result<CreatedApiKey, AuthError> {
+        let key = format!(
+            "{KEY_PREFIX}{}{}",
+            uuid::Uuid::new_v4().simple(),
+            uuid::Uuid::new_v4().simple()
+        );
+        let hash_input = key.clone();
+        let key_hash =
+            tokio::task::spawn_blocking(move || bcrypt::hash(hash_input, bcrypt::DEFAULT_COST))
+                .await
+                .map_err(|e| AuthError::Hashing(e.to_string()))?
+                .map_err(|e| AuthError::Hashing(e.to_string()))?;
+        let scopes: Vec<String> = scopes.iter().map(|s| s.as_str().to_string()).collect();
+
+        let row = sqlx::query!(
+            r#"
+            INSERT INTO api_key (id, name, prefix, key_hash, scopes, tenant_id)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id, name, prefix, scopes, tenant_id, created_at, last_used_at
+            "#,
+            uuid::Uuid::new_v4(),
+            name,
+            &key[..LOOKUP_LENGTH],
+            key_hash,
+            &scopes,
+            tenant_id
+        )
+        .fetch_one(pool)
+        .await?;
+
+        Ok(CreatedApiKey {
+            api_key: ApiKey {
+                id: row.id,
+                name: row.name,
+                prefix: row.prefix,
+                scopes: parse_scopes(&row.scopes),
+                tenant_id: row.tenant_id,
+                created_at: row.created_at,
+                last_used_at: row.last_used_at,
+            },
+            key,
+        })
+    }
+
+    pub async fn list_keys(&self, pool: &PgPool) -> Result<Vec<ApiKey>, sqlx::Error> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT id, name, prefix, scopes, tenant_id, created_at, last_used_at
+            FROM api_key
+            WHERE revoked_at IS NULL
+            ORDER BY created_at
+            "#
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| ApiKey {
+                id: row.id,
+                name: row.name,
+                prefix: row.prefix,
+                scopes: parse_scopes(&row.scopes),
+                tenant_id: row.tenant_id,
+                created_at: row.created_at,
+                last_used_at: row.last_used_at,
+            })
+            .collect())
+    }
+
+    /// Revokes a key, returning whether it existed. Other servers keep accepting it
+    /// until their cached copy expires.
+    pub async fn revoke_key(&self, pool: &PgPool, id: uuid::Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            "UPDATE api_key SET revoked_at = NOW() WHERE id = $1 AND revoked_at IS NULL",
+            id
+        )
+        .execute(pool)
+        .await?;
+
+        self.cache
+            .lock()
+            .unwrap()
+            .retain(|_, cached| cached.key.id != Some(id));
+
+        Ok(result.rows_affected() > 0)
+    }
+}
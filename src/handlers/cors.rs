@@ -0,0 +1,43 @@
+use axum::http::{HeaderValue, Method, header};
+use tower_http::cors::CorsLayer;
+
+#[derive(Clone, Debug, Default)]
+pub struct CorsConfig {
+    /// Origins allowed to call the API from a browser. Any origin is allowed when unset.
+    pub allowed_origins: Option<Vec<HeaderValue>>,
+}
+
+impl CorsConfig {
+    pub fn from_env() -> Self {
+        let allowed_origins = std::env::var("CORS_ALLOWED_ORIGINS").ok().map(|v| {
+            v.split(',')
+                .map(|origin| {
+                    origin
+                        .trim()
+                        .parse()
+                        .expect("CORS_ALLOWED_ORIGINS must be a comma-separated list of origins")
+                })
+                .collect()
+        });
+
+        CorsConfig { allowed_origins }
+    }
+
+    /// Builds the layer, also allowing `tenant_header` so browsers can pick a tenant.
+    pub fn layer(self, tenant_header: &str) -> CorsLayer {
+        match self.allowed_origins {
+            None => CorsLayer::permissive(),
+            Some(origins) => CorsLayer::new()
+                .allow_origin(origins)
+                .allow_methods([Method::GET, Method::POST, Method::DELETE])
+                .allow_headers([
+                    header::AUTHORIZATION,
+                    header::CONTENT_TYPE,
+                    header::HeaderName::from_static("x-api-key"),
+                    tenant_header
+                        .parse()
+                        .expect("TENANT_HEADER must be a valid header name"),
+                ]),
+        }
+    }
+}
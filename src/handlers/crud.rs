@@ -1,18 +1,24 @@
 use rust_decimal::prelude::ToPrimitive;
 use serde::{Deserialize, Serialize};
 use sqlx::{Postgres, QueryBuilder};
-use std::collections::HashMap;
-use time::OffsetDateTime;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use time::{Date, OffsetDateTime, UtcOffset};
 
-use opentelemetry_proto::tonic::collector;
 use opentelemetry_proto::tonic::collector::logs::v1::ExportLogsServiceRequest;
 use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
-use opentelemetry_proto::tonic::common::v1::{AnyValue, any_value::Value};
+use opentelemetry_proto::tonic::common::v1::{AnyValue, KeyValue, any_value::Value};
 use opentelemetry_proto::tonic::logs::v1::LogRecord;
 use opentelemetry_proto::tonic::resource::v1::Resource;
 use opentelemetry_proto::tonic::trace::v1::{ScopeSpans, Span};
 
-#[derive(Clone, Debug, PartialEq, PartialOrd, sqlx::Type, Serialize, Deserialize)]
+use super::pg_copy::{CopyEncoder, copy_in};
+use super::processors::Processors;
+use super::redaction::Redactor;
+use super::tenancy::default_tenant;
+
+#[derive(
+    Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, sqlx::Type, Serialize, Deserialize,
+)]
 #[sqlx(type_name = "span_kind", rename_all = "UPPERCASE")]
 pub enum DbSpanKind {
     Unspecified = 0,
@@ -23,17 +29,53 @@ pub enum DbSpanKind {
     Consumer = 5,
 }
 
+impl DbSpanKind {
+    /// The `span_kind` enum label.
+    pub fn label(&self) -> &'static str {
+        match self {
+            DbSpanKind::Unspecified => "UNSPECIFIED",
+            DbSpanKind::Internal => "INTERNAL",
+            DbSpanKind::Server => "SERVER",
+            DbSpanKind::Client => "CLIENT",
+            DbSpanKind::Producer => "PRODUCER",
+            DbSpanKind::Consumer => "CONSUMER",
+        }
+    }
+
+    /// Inverse of [`Self::label`].
+    pub fn from_label(label: &str) -> Option<Self> {
+        match label {
+            "UNSPECIFIED" => Some(DbSpanKind::Unspecified),
+            "INTERNAL" => Some(DbSpanKind::Internal),
+            "SERVER" => Some(DbSpanKind::Server),
+            "CLIENT" => Some(DbSpanKind::Client),
+            "PRODUCER" => Some(DbSpanKind::Producer),
+            "CONSUMER" => Some(DbSpanKind::Consumer),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum SpanAttributeValue {
     String(String),
     Int(i64),
+    #[serde(deserialize_with = "float_or_null")]
     Float(f64),
     Bool(bool),
 }
 
+/// JSON has no NaN or infinity, so non-finite floats are written as `null`; read them
+/// back as NaN so spooled batches holding them can still be replayed.
+fn float_or_null<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    Ok(Option::<f64>::deserialize(deserializer)?.unwrap_or(f64::NAN))
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct WriteableSpan {
+    #[serde(default = "default_tenant")]
+    pub tenant_id: String,
     pub span_id: String,
     pub trace_id: String,
     pub parent_span_id: Option<String>,
@@ -53,6 +95,8 @@ pub struct WriteableSpan {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct WriteableTrace {
+    #[serde(default = "default_tenant")]
+    pub tenant_id: String,
     pub trace_id: String,
     #[serde(with = "time::serde::rfc3339")]
     pub start_time: OffsetDateTime,
@@ -64,6 +108,8 @@ pub struct WriteableTrace {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct WriteableLog {
+    #[serde(default = "default_tenant")]
+    pub tenant_id: String,
     pub log_id: uuid::Uuid,
     pub trace_id: Option<String>,
     pub span_id: Option<String>,
@@ -78,11 +124,15 @@ pub struct WriteableLog {
     pub service_name: Option<String>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct WriteableLogAttribute {
-    log_id: uuid::Uuid,
+    #[serde(default = "default_tenant")]
+    tenant_id: String,
+    pub log_id: uuid::Uuid,
     key: String,
     value: String,
+    /// The owning log's timestamp, which is `log_attribute`'s partition key.
+    timestamp: OffsetDateTime,
 }
 
 pub trait SpanExt {
@@ -133,12 +183,14 @@ impl SpanExt for Span {
         )?)
     }
 
+    /// Negative when the end is before the start, which `SpanValidator` corrects.
     fn duration_ns(&self) -> Result<i64, Box<dyn std::error::Error>> {
-        Ok((self.end_time_unix_nano - self.start_time_unix_nano) as i64)
+        let duration_ns = self.end_time_unix_nano as i128 - self.start_time_unix_nano as i128;
+        Ok(i64::try_from(duration_ns)?)
     }
 
     fn status_code(&self) -> i32 {
-        self.status.as_ref().map(|s| s.code as i32).unwrap_or(0)
+        self.status.as_ref().map(|s| s.code).unwrap_or(0)
     }
 
     fn status_message(&self) -> Option<String> {
@@ -300,6 +352,12 @@ fn any_value_to_span_attribute(value: &Option<AnyValue>) -> SpanAttributeValue {
     }
 }
 
+fn resource_key_values(resource: &Option<Resource>) -> &[KeyValue] {
+    resource
+        .as_ref()
+        .map_or(&[], |resource| resource.attributes.as_slice())
+}
+
 fn extract_service_name(resource: &Option<Resource>) -> Option<String> {
     resource
         .as_ref()?
@@ -319,71 +377,460 @@ fn extract_instrumentation_library(scope_spans: &ScopeSpans) -> Option<String> {
     Some(scope_spans.scope.as_ref()?.name.clone())
 }
 
+/// Maps a write error to `Unavailable` when the database couldn't be reached, so the
+/// batch can be retried, or `Internal` when it rejected the data.
+pub fn database_error(e: sqlx::Error) -> tonic::Status {
+    let unavailable = match &e {
+        sqlx::Error::Io(_)
+        | sqlx::Error::Tls(_)
+        | sqlx::Error::PoolTimedOut
+        | sqlx::Error::PoolClosed
+        | sqlx::Error::WorkerCrashed => true,
+        // Connection exceptions, insufficient resources and shutdowns.
+        sqlx::Error::Database(e) => e.code().is_some_and(|code| {
+            code.starts_with("08") || code.starts_with("53") || code.starts_with("57P")
+        }),
+        _ => false,
+    };
+
+    if unavailable {
+        tonic::Status::unavailable(format!("Database unavailable: {}", e))
+    } else {
+        tonic::Status::internal(format!("Database error: {}", e))
+    }
+}
+
+/// Postgres accepts at most this many bind parameters per statement, so multi-row
+/// inserts are split into chunks of `MAX_BIND_PARAMS / <binds per row>` rows.
+const MAX_BIND_PARAMS: usize = 65535;
+
 pub async fn insert_traces(
-    traces: &Vec<WriteableTrace>,
+    traces: &[WriteableTrace],
     tx: &mut sqlx::Transaction<'_, Postgres>,
 ) -> Result<(), tonic::Status> {
     if traces.is_empty() {
         return Ok(());
     }
 
-    let mut query_builder: QueryBuilder<Postgres> =
-        QueryBuilder::new("INSERT INTO trace (id, started_at, ended_at, duration_ns, span_count) ");
+    for chunk in traces.chunks(MAX_BIND_PARAMS / 6) {
+        let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new(
+            "INSERT INTO trace (tenant_id, id, started_at, ended_at, duration_ns, span_count) ",
+        );
+
+        query_builder.push_values(chunk, |mut b, trace| {
+            b.push_bind(trace.tenant_id.clone())
+                .push_bind(trace.trace_id.clone())
+                .push_bind(trace.start_time)
+                .push_bind(trace.end_time)
+                .push_bind(trace.duration_ns)
+                .push_bind(trace.span_count);
+        });
+
+        // Spans of one trace can arrive over several requests, so widen the existing row.
+        query_builder.push(
+            r#"
+            ON CONFLICT (tenant_id, id) DO UPDATE SET
+                started_at = LEAST(trace.started_at, EXCLUDED.started_at),
+                ended_at = GREATEST(trace.ended_at, EXCLUDED.ended_at),
+                duration_ns = (
+                    EXTRACT(EPOCH FROM
+                        GREATEST(trace.ended_at, EXCLUDED.ended_at)
+                        - LEAST(trace.started_at, EXCLUDED.started_at)
+                    ) * 1000000000
+                )::BIGINT,
+                span_count = trace.span_count + EXCLUDED.span_count
+            "#,
+        );
+
+        let query = query_builder.build();
+        query.execute(&mut **tx).await.map_err(database_error)?;
+    }
+
+    Ok(())
+}
 
-    query_builder.push_values(traces, |mut b, trace| {
-        b.push_bind(trace.trace_id.clone())
-            .push_bind(trace.start_time)
-            .push_bind(trace.end_time)
-            .push_bind(trace.duration_ns)
-            .push_bind(trace.span_count);
-    });
+/// Creates the daily partitions of `table` that `timestamps` fall in. The partitioned
+/// tables have no default partition, so a row for a day without one would be rejected.
+async fn create_partitions_for(
+    table: &str,
+    timestamps: impl Iterator<Item = OffsetDateTime>,
+    tx: &mut sqlx::Transaction<'_, Postgres>,
+) -> Result<(), tonic::Status> {
+    let days: BTreeSet<Date> = timestamps
+        .map(|timestamp| timestamp.to_offset(UtcOffset::UTC).date())
+        .collect();
 
-    let query = query_builder.build();
-    query
-        .execute(&mut **tx)
-        .await
-        .map_err(|e| tonic::Status::internal(format!("Database error: {}", e)))?;
+    sqlx::query!(
+        "SELECT create_daily_partition($1, day) FROM UNNEST($2::DATE[]) AS day",
+        table,
+        &days.into_iter().collect::<Vec<_>>(),
+    )
+    .execute(&mut **tx)
+    .await
+    .map_err(database_error)?;
 
     Ok(())
 }
 
+/// Inserts `spans`, skipping those already stored (e.g. by a retried export), and
+/// returns the ones inserted. Rollups and the catalog only count inserted spans.
 pub async fn insert_spans(
-    spans: &Vec<WriteableSpan>,
+    spans: &[WriteableSpan],
+    tx: &mut sqlx::Transaction<'_, Postgres>,
+) -> Result<Vec<WriteableSpan>, tonic::Status> {
+    if spans.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // COPY can't skip conflicting rows, so spans are copied into a staging table first.
+    sqlx::query(
+        "CREATE TEMPORARY TABLE span_staging (LIKE span INCLUDING DEFAULTS) ON COMMIT DROP",
+    )
+    .execute(&mut **tx)
+    .await
+    .map_err(database_error)?;
+
+    let mut encoder = CopyEncoder::new();
+    for span in spans {
+        encoder
+            .row(14)
+            .text(&span.tenant_id)
+            .text(&span.span_id)
+            .text(&span.trace_id)
+            .optional_text(span.parent_span_id.as_deref())
+            .text(&span.operation_name)
+            .timestamptz(span.start_time)
+            .timestamptz(span.end_time)
+            .int8(span.duration_ns)
+            .int4(span.status_code)
+            .optional_text(span.status_message.as_deref())
+            .text(span.span_kind.label())
+            .optional_text(span.instrumentation_library.as_deref())
+            .optional_text(span.service_name.as_deref())
+            .jsonb(&span.attributes);
+    }
+
+    copy_in(
+        "COPY span_staging (
+            tenant_id, id, trace_id, parent_span_id, operation_name, started_at, ended_at, duration_ns,
+            status_code, status_message, kind, instrumentation_library, service_name, attributes
+        ) FROM STDIN (FORMAT BINARY)",
+        encoder,
+        tx,
+    )
+    .await
+    .map_err(database_error)?;
+
+    create_partitions_for("span", spans.iter().map(|span| span.start_time), tx).await?;
+
+    let inserted: Vec<(String, String, String)> = sqlx::query_as(
+        "INSERT INTO span SELECT * FROM span_staging ON CONFLICT DO NOTHING
+        RETURNING tenant_id, trace_id, id",
+    )
+    .fetch_all(&mut **tx)
+    .await
+    .map_err(database_error)?;
+
+    // Removing matched keys also skips a span repeated within `spans`.
+    let mut inserted: HashSet<(String, String, String)> = inserted.into_iter().collect();
+    let spans: Vec<WriteableSpan> = spans
+        .iter()
+        .filter(|span| {
+            inserted.remove(&(
+                span.tenant_id.clone(),
+                span.trace_id.clone(),
+                span.span_id.clone(),
+            ))
+        })
+        .cloned()
+        .collect();
+
+    insert_span_rollups(&spans, tx).await?;
+    upsert_span_catalog(&spans, tx).await?;
+
+    Ok(spans)
+}
+
+fn widen_seen_range<K: Ord>(
+    seen: &mut BTreeMap<K, (OffsetDateTime, OffsetDateTime)>,
+    key: K,
+    time: OffsetDateTime,
+) {
+    let (first_seen, last_seen) = seen.entry(key).or_insert((time, time));
+    *first_seen = (*first_seen).min(time);
+    *last_seen = (*last_seen).max(time);
+}
+
+/// Records the services, operations and attribute keys in `spans` in the catalog tables.
+/// Keys are upserted in sorted order so concurrent ingests lock rows in the same order.
+pub async fn upsert_span_catalog(
+    spans: &[WriteableSpan],
     tx: &mut sqlx::Transaction<'_, Postgres>,
 ) -> Result<(), tonic::Status> {
     if spans.is_empty() {
         return Ok(());
     }
 
-    let mut query_builder = QueryBuilder::new(
-        "INSERT INTO span (
-                    id, trace_id, parent_span_id, operation_name,
-                    started_at, ended_at, duration_ns, status_code, status_message, kind, instrumentation_library, service_name, attributes
-                ) ",
-    );
-
-    query_builder.push_values(spans, |mut b, span| {
-        b.push_bind(span.span_id.clone())
-            .push_bind(span.trace_id.clone())
-            .push_bind(span.parent_span_id.clone())
-            .push_bind(span.operation_name.clone())
-            .push_bind(span.start_time)
-            .push_bind(span.end_time)
-            .push_bind(span.duration_ns)
-            .push_bind(span.status_code)
-            .push_bind(span.status_message.clone())
-            .push_bind(span.span_kind.clone())
-            .push_bind(span.instrumentation_library.clone())
-            .push_bind(span.service_name.clone())
-            .push_bind(sqlx::types::Json(&span.attributes));
-    });
-
-    let query = query_builder.build();
-
-    query
-        .execute(&mut **tx)
-        .await
-        .map_err(|e| tonic::Status::internal(format!("Database error: {}", e)))?;
+    let mut services: BTreeMap<(String, String), (OffsetDateTime, OffsetDateTime)> =
+        BTreeMap::new();
+    let mut operations: BTreeMap<
+        (String, String, String, DbSpanKind),
+        (OffsetDateTime, OffsetDateTime),
+    > = BTreeMap::new();
+    let mut attribute_keys: BTreeMap<(String, String, String), (OffsetDateTime, OffsetDateTime)> =
+        BTreeMap::new();
+
+    for span in spans {
+        let tenant_id = span.tenant_id.clone();
+        let service_name = span.service_name.clone().unwrap_or_default();
+
+        widen_seen_range(
+            &mut services,
+            (tenant_id.clone(), service_name.clone()),
+            span.start_time,
+        );
+        widen_seen_range(
+            &mut operations,
+            (
+                tenant_id.clone(),
+                service_name.clone(),
+                span.operation_name.clone(),
+                span.span_kind.clone(),
+            ),
+            span.start_time,
+        );
+        for key in span.attributes.keys() {
+            widen_seen_range(
+                &mut attribute_keys,
+                (tenant_id.clone(), service_name.clone(), key.clone()),
+                span.start_time,
+            );
+        }
+    }
+
+    let services: Vec<_> = services.into_iter().collect();
+    for chunk in services.chunks(MAX_BIND_PARAMS / 4) {
+        let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new(
+            "INSERT INTO service_catalog (tenant_id, service_name, first_seen, last_seen) ",
+        );
+
+        query_builder.push_values(
+            chunk,
+            |mut b, ((tenant_id, service_name), (first_seen, last_seen))| {
+                b.push_bind(tenant_id.clone())
+                    .push_bind(service_name.clone())
+                    .push_bind(*first_seen)
+                    .push_bind(*last_seen);
+            },
+        );
+
+        query_builder.push(
+            " ON CONFLICT (tenant_id, service_name) DO UPDATE SET
+                first_seen = LEAST(service_catalog.first_seen, EXCLUDED.first_seen),
+                last_seen = GREATEST(service_catalog.last_seen, EXCLUDED.last_seen)
+            WHERE service_catalog.first_seen > EXCLUDED.first_seen
+                OR service_catalog.last_seen < EXCLUDED.last_seen",
+        );
+
+        query_builder
+            .build()
+            .execute(&mut **tx)
+            .await
+            .map_err(database_error)?;
+    }
+
+    let operations: Vec<_> = operations.into_iter().collect();
+    for chunk in operations.chunks(MAX_BIND_PARAMS / 6) {
+        let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new(
+            "INSERT INTO operation_catalog (
+                tenant_id, service_name, operation_name, kind, first_seen, last_seen
+            ) ",
+        );
+
+        query_builder.push_values(
+            chunk,
+            |mut b, ((tenant_id, service_name, operation_name, kind), (first_seen, last_seen))| {
+                b.push_bind(tenant_id.clone())
+                    .push_bind(service_name.clone())
+                    .push_bind(operation_name.clone())
+                    .push_bind(kind.clone())
+                    .push_bind(*first_seen)
+                    .push_bind(*last_seen);
+            },
+        );
+
+        query_builder.push(
+            " ON CONFLICT (tenant_id, service_name, operation_name, kind) DO UPDATE SET
+                first_seen = LEAST(operation_catalog.first_seen, EXCLUDED.first_seen),
+                last_seen = GREATEST(operation_catalog.last_seen, EXCLUDED.last_seen)
+            WHERE operation_catalog.first_seen > EXCLUDED.first_seen
+                OR operation_catalog.last_seen < EXCLUDED.last_seen",
+        );
+
+        query_builder
+            .build()
+            .execute(&mut **tx)
+            .await
+            .map_err(database_error)?;
+    }
+
+    if attribute_keys.is_empty() {
+        return Ok(());
+    }
+
+    let attribute_keys: Vec<_> = attribute_keys.into_iter().collect();
+    for chunk in attribute_keys.chunks(MAX_BIND_PARAMS / 5) {
+        let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new(
+            "INSERT INTO span_attribute_catalog (tenant_id, service_name, key, first_seen, last_seen) ",
+        );
+
+        query_builder.push_values(
+            chunk,
+            |mut b, ((tenant_id, service_name, key), (first_seen, last_seen))| {
+                b.push_bind(tenant_id.clone())
+                    .push_bind(service_name.clone())
+                    .push_bind(key.clone())
+                    .push_bind(*first_seen)
+                    .push_bind(*last_seen);
+            },
+        );
+
+        query_builder.push(
+            " ON CONFLICT (tenant_id, service_name, key) DO UPDATE SET
+                first_seen = LEAST(span_attribute_catalog.first_seen, EXCLUDED.first_seen),
+                last_seen = GREATEST(span_attribute_catalog.last_seen, EXCLUDED.last_seen)
+            WHERE span_attribute_catalog.first_seen > EXCLUDED.first_seen
+                OR span_attribute_catalog.last_seen < EXCLUDED.last_seen",
+        );
+
+        query_builder
+            .build()
+            .execute(&mut **tx)
+            .await
+            .map_err(database_error)?;
+    }
+
+    Ok(())
+}
+
+pub const ROLLUP_HISTOGRAM_BUCKETS: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct SpanRollupKey {
+    tenant_id: String,
+    bucket: OffsetDateTime,
+    service_name: String,
+    operation_name: String,
+    span_kind: DbSpanKind,
+}
+
+#[derive(Clone, Debug)]
+struct SpanRollup {
+    span_count: i64,
+    error_count: i64,
+    duration_sum_ns: i64,
+    duration_min_ns: i64,
+    duration_max_ns: i64,
+    duration_histogram: Vec<i64>,
+}
+
+/// Index of the log2-scaled bucket holding `duration_ns`, i.e. `floor(log2(duration_ns))`.
+pub fn duration_histogram_bucket(duration_ns: i64) -> usize {
+    if duration_ns <= 1 {
+        0
+    } else {
+        (63 - duration_ns.leading_zeros()) as usize
+    }
+}
+
+fn minute_bucket(time: OffsetDateTime) -> OffsetDateTime {
+    time - time::Duration::seconds(time.unix_timestamp().rem_euclid(60))
+        - time::Duration::nanoseconds(time.nanosecond() as i64)
+}
+
+/// Folds `spans` into the per-minute `span_rollup_1m` aggregates.
+pub async fn insert_span_rollups(
+    spans: &[WriteableSpan],
+    tx: &mut sqlx::Transaction<'_, Postgres>,
+) -> Result<(), tonic::Status> {
+    // Ordered so that concurrent ingests lock the same rows in the same order.
+    let mut rollups: BTreeMap<SpanRollupKey, SpanRollup> = BTreeMap::new();
+
+    for span in spans {
+        let key = SpanRollupKey {
+            tenant_id: span.tenant_id.clone(),
+            bucket: minute_bucket(span.start_time),
+            service_name: span.service_name.clone().unwrap_or_default(),
+            operation_name: span.operation_name.clone(),
+            span_kind: span.span_kind.clone(),
+        };
+
+        let rollup = rollups.entry(key).or_insert_with(|| SpanRollup {
+            span_count: 0,
+            error_count: 0,
+            duration_sum_ns: 0,
+            duration_min_ns: span.duration_ns,
+            duration_max_ns: span.duration_ns,
+            duration_histogram: vec![0; ROLLUP_HISTOGRAM_BUCKETS],
+        });
+
+        rollup.span_count += 1;
+        if span.status_code == 2 {
+            rollup.error_count += 1;
+        }
+        rollup.duration_sum_ns = rollup.duration_sum_ns.saturating_add(span.duration_ns);
+        rollup.duration_min_ns = rollup.duration_min_ns.min(span.duration_ns);
+        rollup.duration_max_ns = rollup.duration_max_ns.max(span.duration_ns);
+        rollup.duration_histogram[duration_histogram_bucket(span.duration_ns)] += 1;
+    }
+
+    if rollups.is_empty() {
+        return Ok(());
+    }
+
+    let rollups: Vec<(SpanRollupKey, SpanRollup)> = rollups.into_iter().collect();
+
+    for chunk in rollups.chunks(MAX_BIND_PARAMS / 11) {
+        let mut query_builder = QueryBuilder::new(
+            "INSERT INTO span_rollup_1m (
+                tenant_id, bucket, service_name, operation_name, kind, span_count, error_count,
+                duration_sum_ns, duration_min_ns, duration_max_ns, duration_histogram
+            ) ",
+        );
+
+        query_builder.push_values(chunk, |mut b, (key, rollup)| {
+            b.push_bind(key.tenant_id.clone())
+                .push_bind(key.bucket)
+                .push_bind(key.service_name.clone())
+                .push_bind(key.operation_name.clone())
+                .push_bind(key.span_kind.clone())
+                .push_bind(rollup.span_count)
+                .push_bind(rollup.error_count)
+                .push_bind(rollup.duration_sum_ns)
+                .push_bind(rollup.duration_min_ns)
+                .push_bind(rollup.duration_max_ns)
+                .push_bind(rollup.duration_histogram.clone());
+        });
+
+        query_builder.push(
+            " ON CONFLICT (tenant_id, bucket, service_name, operation_name, kind) DO UPDATE SET
+                span_count = span_rollup_1m.span_count + EXCLUDED.span_count,
+                error_count = span_rollup_1m.error_count + EXCLUDED.error_count,
+                duration_sum_ns = span_rollup_1m.duration_sum_ns + EXCLUDED.duration_sum_ns,
+                duration_min_ns = LEAST(span_rollup_1m.duration_min_ns, EXCLUDED.duration_min_ns),
+                duration_max_ns = GREATEST(span_rollup_1m.duration_max_ns, EXCLUDED.duration_max_ns),
+                duration_histogram = ARRAY(
+                    SELECT a + b
+                    FROM UNNEST(span_rollup_1m.duration_histogram, EXCLUDED.duration_histogram)
+                        WITH ORDINALITY AS h(a, b, i)
+                    ORDER BY i
+                )",
+        );
+
+        let query = query_builder.build();
+
+        query.execute(&mut **tx).await.map_err(database_error)?;
+    }
 
     Ok(())
 }
@@ -396,31 +843,35 @@ pub async fn insert_logs(
         return Ok(());
     }
 
-    let mut query_builder = QueryBuilder::new(
-        "INSERT INTO log (
-            id, trace_id, span_id, timestamp, observed_timestamp,
+    let mut encoder = CopyEncoder::new();
+    for log in logs {
+        encoder
+            .row(11)
+            .text(&log.tenant_id)
+            .uuid(log.log_id)
+            .optional_text(log.trace_id.as_deref())
+            .optional_text(log.span_id.as_deref())
+            .timestamptz(log.timestamp)
+            .optional_timestamptz(log.observed_timestamp)
+            .int4(log.severity_number)
+            .optional_text(log.severity_text.as_deref())
+            .optional_text(log.body.as_deref())
+            .optional_text(log.instrumentation_library.as_deref())
+            .optional_text(log.service_name.as_deref());
+    }
+
+    create_partitions_for("log", logs.iter().map(|log| log.timestamp), tx).await?;
+
+    copy_in(
+        "COPY log (
+            tenant_id, id, trace_id, span_id, timestamp, observed_timestamp,
             severity_number, severity_text, body, instrumentation_library, service_name
-        ) ",
-    );
-
-    query_builder.push_values(logs, |mut b, log| {
-        b.push_bind(log.log_id.clone())
-            .push_bind(log.trace_id.clone())
-            .push_bind(log.span_id.clone())
-            .push_bind(log.timestamp)
-            .push_bind(log.observed_timestamp)
-            .push_bind(log.severity_number)
-            .push_bind(log.severity_text.clone())
-            .push_bind(log.body.clone())
-            .push_bind(log.instrumentation_library.clone())
-            .push_bind(log.service_name.clone());
-    });
-
-    let query = query_builder.build();
-    query
-        .execute(&mut **tx)
-        .await
-        .map_err(|e| tonic::Status::internal(format!("Database error: {}", e)))?;
+        ) FROM STDIN (FORMAT BINARY)",
+        encoder,
+        tx,
+    )
+    .await
+    .map_err(database_error)?;
 
     Ok(())
 }
@@ -433,204 +884,270 @@ pub async fn insert_log_attributes(
         return Ok(());
     }
 
-    let mut query_builder = QueryBuilder::new("INSERT INTO log_attribute (log_id, key, value) ");
-
-    query_builder.push_values(log_attributes, |mut b, attr| {
-        b.push_bind(attr.log_id.clone())
-            .push_bind(attr.key.clone())
-            .push_bind(attr.value.clone());
-    });
+    let mut encoder = CopyEncoder::new();
+    for attr in log_attributes {
+        encoder
+            .row(5)
+            .text(&attr.tenant_id)
+            .uuid(attr.log_id)
+            .text(&attr.key)
+            .text(&attr.value)
+            .timestamptz(attr.timestamp);
+    }
 
-    let query = query_builder.build();
-    query
-        .execute(&mut **tx)
-        .await
-        .map_err(|e| tonic::Status::internal(format!("Database error: {}", e)))?;
+    create_partitions_for(
+        "log_attribute",
+        log_attributes.iter().map(|attr| attr.timestamp),
+        tx,
+    )
+    .await?;
+
+    copy_in(
+        "COPY log_attribute (tenant_id, log_id, key, value, timestamp) FROM STDIN (FORMAT BINARY)",
+        encoder,
+        tx,
+    )
+    .await
+    .map_err(database_error)?;
 
     Ok(())
 }
 
+/// One trace row per tenant and trace id in `spans`, ordered by both.
+pub fn traces_from_spans(spans: &[WriteableSpan]) -> Vec<WriteableTrace> {
+    let mut trace_id_to_info: BTreeMap<(&str, &str), (OffsetDateTime, OffsetDateTime, i32)> =
+        BTreeMap::new();
+
+    for span in spans {
+        trace_id_to_info
+            .entry((&span.tenant_id, &span.trace_id))
+            .and_modify(|(start_time, end_time, span_count)| {
+                *start_time = (*start_time).min(span.start_time);
+                *end_time = (*end_time).max(span.end_time);
+                *span_count += 1;
+            })
+            .or_insert((span.start_time, span.end_time, 1));
+    }
+
+    trace_id_to_info
+        .into_iter()
+        .map(
+            |((tenant_id, trace_id), (start_time, end_time, span_count))| WriteableTrace {
+                tenant_id: tenant_id.to_string(),
+                trace_id: trace_id.to_string(),
+                start_time,
+                end_time,
+                duration_ns: (end_time - start_time).whole_nanoseconds().to_i64(),
+                span_count,
+            },
+        )
+        .collect()
+}
+
+/// Boxed so the conversions below don't carry a large `Err` variant.
+fn invalid_argument(message: String) -> Box<tonic::Status> {
+    Box::new(tonic::Status::invalid_argument(message))
+}
+
+fn writeable_span(
+    span: &Span,
+    tenant_id: &str,
+    instrumentation_library: &Option<String>,
+    service_name: &Option<String>,
+) -> Result<WriteableSpan, Box<tonic::Status>> {
+    let trace_id = span
+        .trace_id_hex()
+        .map_err(|e| invalid_argument(format!("Invalid trace ID: {}", e)))?;
+    let span_id = span
+        .span_id_hex()
+        .map_err(|e| invalid_argument(format!("Invalid span ID: {}", e)))?;
+    let start_time = span
+        .start_time()
+        .map_err(|e| invalid_argument(format!("Invalid start time: {}", e)))?;
+    let end_time = span
+        .end_time()
+        .map_err(|e| invalid_argument(format!("Invalid end time: {}", e)))?;
+    let duration_ns = span
+        .duration_ns()
+        .map_err(|e| invalid_argument(format!("Invalid duration: {}", e)))?;
+
+    Ok(WriteableSpan {
+        tenant_id: tenant_id.to_string(),
+        span_id,
+        trace_id,
+        parent_span_id: span.parent_span_id_hex(),
+        operation_name: span.name.clone(),
+        start_time,
+        end_time,
+        duration_ns,
+        status_code: span.status_code(),
+        status_message: span.status_message(),
+        span_kind: span.span_kind_to_db(),
+        instrumentation_library: instrumentation_library.clone(),
+        service_name: service_name.clone(),
+        attributes: span.attributes_typed(),
+    })
+}
+
+/// Converts every valid span in `payload` into a span of `tenant_id`, running
+/// `processors` and then redacting attributes with `redactor`. Invalid spans are skipped
+/// and their errors returned alongside, so one bad span doesn't reject the whole export.
 pub fn flatten_spans(
     payload: &ExportTraceServiceRequest,
-) -> Result<(Vec<WriteableTrace>, Vec<WriteableSpan>), tonic::Status> {
-    let mut trace_id_to_info = HashMap::new();
-
-    for resource_span in payload.resource_spans.iter() {
-        for scope_span in resource_span.scope_spans.iter() {
-            for span in scope_span.spans.iter() {
-                let span_start_time = span.start_time().map_err(|e| {
-                    tonic::Status::invalid_argument(format!("Invalid start time: {}", e))
-                })?;
-                let span_end_time = span.end_time().map_err(|e| {
-                    tonic::Status::invalid_argument(format!("Invalid end time: {}", e))
-                })?;
-                let trace_id = span.trace_id_hex().map_err(|e| {
-                    tonic::Status::invalid_argument(format!("Invalid trace ID: {}", e))
-                })?;
-
-                use std::collections::hash_map::Entry;
-
-                match trace_id_to_info.entry(trace_id.clone()) {
-                    Entry::Vacant(e) => {
-                        e.insert((span_start_time, span_end_time, 1));
-                    }
-                    Entry::Occupied(mut e) => {
-                        let (start_time, end_time, span_count) = e.get_mut();
-                        if *start_time > span_start_time {
-                            *start_time = span_start_time;
-                        }
-                        if *end_time < span_end_time {
-                            *end_time = span_end_time;
+    tenant_id: &str,
+    processors: &Processors,
+    redactor: &Redactor,
+) -> (Vec<WriteableSpan>, Vec<tonic::Status>) {
+    let mut spans = Vec::new();
+    let mut errors = Vec::new();
+
+    for resource_span in &payload.resource_spans {
+        let service_name = extract_service_name(&resource_span.resource);
+        let resource_attributes: HashMap<String, SpanAttributeValue> =
+            resource_key_values(&resource_span.resource)
+                .iter()
+                .map(|kv| (kv.key.clone(), any_value_to_span_attribute(&kv.value)))
+                .collect();
+
+        for scope_span in &resource_span.scope_spans {
+            let instrumentation_library = extract_instrumentation_library(scope_span);
+
+            for span in &scope_span.spans {
+                match writeable_span(span, tenant_id, &instrumentation_library, &service_name) {
+                    Ok(mut span) => {
+                        if !processors.process_span(&mut span, &resource_attributes) {
+                            continue;
                         }
-                        *span_count += 1;
+                        redactor.redact_span_attributes(
+                            span.service_name.as_deref(),
+                            &mut span.attributes,
+                        );
+                        spans.push(span);
                     }
+                    Err(e) => errors.push(*e),
                 }
             }
         }
     }
 
-    let mut traces: Vec<WriteableTrace> = Vec::new();
-    for (trace_id, (start_time, end_time, span_count)) in &trace_id_to_info {
-        let duration_ns = (*end_time - *start_time).whole_nanoseconds().to_i64();
-        let trace = WriteableTrace {
-            trace_id: trace_id.clone(),
-            start_time: *start_time,
-            end_time: *end_time,
-            duration_ns,
-            span_count: *span_count,
-        };
-        traces.push(trace);
-    }
-
-    let spans: Result<Vec<WriteableSpan>, tonic::Status> = payload
-        .resource_spans
-        .iter()
-        .flat_map(|resource_span| {
-            resource_span
-                .scope_spans
-                .iter()
-                .flat_map(move |scope_span| {
-                    let instrumentation_library = extract_instrumentation_library(scope_span);
-                    let service_name = extract_service_name(&resource_span.resource);
-
-                    scope_span.spans.iter().map(move |span| {
-                        let trace_id = span.trace_id_hex().map_err(|e| {
-                            tonic::Status::invalid_argument(format!("Invalid trace ID: {}", e))
-                        })?;
-                        let span_id = span.span_id_hex().map_err(|e| {
-                            tonic::Status::invalid_argument(format!("Invalid span ID: {}", e))
-                        })?;
-                        let start_time = span.start_time().map_err(|e| {
-                            tonic::Status::invalid_argument(format!("Invalid start time: {}", e))
-                        })?;
-                        let end_time = span.end_time().map_err(|e| {
-                            tonic::Status::invalid_argument(format!("Invalid end time: {}", e))
-                        })?;
-                        let duration_ns = span.duration_ns().map_err(|e| {
-                            tonic::Status::invalid_argument(format!("Invalid duration: {}", e))
-                        })?;
-
-                        let attributes: HashMap<String, SpanAttributeValue> =
-                            span.attributes_typed();
-
-                        let writeable_span = WriteableSpan {
-                            span_id: span_id.clone(),
-                            trace_id,
-                            parent_span_id: span.parent_span_id_hex(),
-                            operation_name: span.name.clone(),
-                            start_time,
-                            end_time,
-                            duration_ns,
-                            status_code: span.status_code(),
-                            status_message: span.status_message(),
-                            span_kind: span.span_kind_to_db(),
-                            instrumentation_library: instrumentation_library.clone(),
-                            service_name: service_name.clone(),
-                            attributes: attributes.clone(),
-                        };
-
-                        Ok(writeable_span)
-                    })
-                })
-        })
-        .collect();
+    (spans, errors)
+}
 
-    Ok((traces, spans?))
+fn writeable_log(
+    log_record: &LogRecord,
+    tenant_id: &str,
+    instrumentation_library: &Option<String>,
+    service_name: &Option<String>,
+) -> Result<(WriteableLog, HashMap<String, String>), Box<tonic::Status>> {
+    let log_id = uuid::Uuid::new_v4();
+
+    let timestamp = log_record
+        .timestamp()
+        .map_err(|e| invalid_argument(format!("Invalid timestamp: {}", e)))?;
+
+    let observed_timestamp = log_record
+        .observed_timestamp()
+        .map_err(|e| invalid_argument(format!("Invalid observed timestamp: {}", e)))?;
+
+    let severity_number: i32 = log_record.severity_number().into();
+
+    let writeable_log = WriteableLog {
+        tenant_id: tenant_id.to_string(),
+        log_id,
+        trace_id: log_record.trace_id_hex(),
+        span_id: log_record.span_id_hex(),
+        timestamp,
+        observed_timestamp,
+        severity_number,
+        severity_text: log_record.severity_text(),
+        body: log_record.body_string(),
+        instrumentation_library: instrumentation_library.clone(),
+        service_name: service_name.clone(),
+    };
+
+    Ok((writeable_log, log_record.attributes_map()))
 }
 
+/// Converts every valid log record in `payload`, like [`flatten_spans`].
 pub fn flatten_logs_and_attrs(
     payload: &ExportLogsServiceRequest,
-) -> Result<(Vec<WriteableLog>, Vec<WriteableLogAttribute>), tonic::Status> {
-    use uuid::Uuid;
-
-    let logs_and_attrs: Result<Vec<(WriteableLog, Vec<WriteableLogAttribute>)>, tonic::Status> =
-        payload
-            .resource_logs
-            .iter()
-            .flat_map(|resource_log| {
-                resource_log.scope_logs.iter().flat_map(move |scope_log| {
-                    let instrumentation_library = scope_log
-                        .scope
-                        .as_ref()
-                        .and_then(|scope| Some(scope.name.clone()));
-                    let service_name = extract_service_name(&resource_log.resource);
-
-                    scope_log.log_records.iter().map(move |log_record| {
-                        let log_id = Uuid::new_v4();
-
-                        let timestamp = log_record.timestamp().map_err(|e| {
-                            tonic::Status::invalid_argument(format!("Invalid timestamp: {}", e))
-                        })?;
-
-                        let observed_timestamp = log_record.observed_timestamp().map_err(|e| {
-                            tonic::Status::invalid_argument(format!(
-                                "Invalid observed timestamp: {}",
-                                e
-                            ))
-                        })?;
-
-                        let severity_number: i32 = log_record.severity_number().into();
-
-                        let writeable_log = WriteableLog {
-                            log_id: log_id.clone(),
-                            trace_id: log_record.trace_id_hex(),
-                            span_id: log_record.span_id_hex(),
-                            timestamp,
-                            observed_timestamp,
-                            severity_number,
-                            severity_text: log_record.severity_text(),
-                            body: log_record.body_string(),
-                            instrumentation_library: instrumentation_library.clone(),
-                            service_name: service_name.clone(),
-                        };
-
-                        let attributes: Vec<WriteableLogAttribute> = log_record
-                            .attributes_map()
-                            .into_iter()
-                            .map(|(k, v)| WriteableLogAttribute {
-                                log_id: log_id.clone(),
-                                key: k,
-                                value: v,
-                            })
-                            .collect();
-
-                        Ok((writeable_log, attributes))
-                    })
-                })
-            })
-            .collect();
-
-    let logs_and_attrs = logs_and_attrs?;
-
-    let logs = logs_and_attrs
-        .iter()
-        .map(|(log, _)| log.clone())
-        .collect::<Vec<WriteableLog>>();
+    tenant_id: &str,
+    processors: &Processors,
+    redactor: &Redactor,
+) -> (
+    Vec<WriteableLog>,
+    Vec<WriteableLogAttribute>,
+    Vec<tonic::Status>,
+) {
+    let mut logs = Vec::new();
+    let mut log_attributes = Vec::new();
+    let mut errors = Vec::new();
+
+    for resource_log in &payload.resource_logs {
+        let service_name = extract_service_name(&resource_log.resource);
+        let resource_attributes: HashMap<String, String> =
+            resource_key_values(&resource_log.resource)
+                .iter()
+                .map(|kv| (kv.key.clone(), any_value_to_string(&kv.value)))
+                .collect();
+
+        for scope_log in &resource_log.scope_logs {
+            let instrumentation_library = scope_log.scope.as_ref().map(|scope| scope.name.clone());
+
+            for log_record in &scope_log.log_records {
+                match writeable_log(
+                    log_record,
+                    tenant_id,
+                    &instrumentation_library,
+                    &service_name,
+                ) {
+                    Ok((mut log, mut attributes)) => {
+                        processors.process_log(&mut log, &mut attributes, &resource_attributes);
+
+                        let service_name = log.service_name.as_deref();
+                        log.body = redactor.redact_log_body(service_name, log.body.take());
+                        log_attributes.extend(
+                            redactor
+                                .redact_log_attributes(service_name, attributes)
+                                .into_iter()
+                                .map(|(key, value)| WriteableLogAttribute {
+                                    tenant_id: log.tenant_id.clone(),
+                                    log_id: log.log_id,
+                                    key,
+                                    value,
+                                    timestamp: log.timestamp,
+                                }),
+                        );
+                        logs.push(log);
+                    }
+                    Err(e) => errors.push(*e),
+                }
+            }
+        }
+    }
 
-    let log_attributes = logs_and_attrs
-        .iter()
-        .flat_map(|(_, attrs)| attrs.iter().cloned())
-        .collect::<Vec<WriteableLogAttribute>>();
+    (logs, log_attributes, errors)
+}
 
-    Ok((logs, log_attributes))
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn non_finite_floats_survive_a_json_round_trip() {
+        let attributes = HashMap::from([
+            ("nan".to_string(), SpanAttributeValue::Float(f64::NAN)),
+            ("inf".to_string(), SpanAttributeValue::Float(f64::INFINITY)),
+            ("ratio".to_string(), SpanAttributeValue::Float(0.5)),
+            ("count".to_string(), SpanAttributeValue::Int(3)),
+        ]);
+
+        let json = serde_json::to_vec(&attributes).unwrap();
+        let decoded: HashMap<String, SpanAttributeValue> = serde_json::from_slice(&json).unwrap();
+
+        assert!(matches!(decoded["nan"], SpanAttributeValue::Float(f) if f.is_nan()));
+        assert!(matches!(decoded["inf"], SpanAttributeValue::Float(f) if f.is_nan()));
+        assert!(matches!(decoded["ratio"], SpanAttributeValue::Float(f) if f == 0.5));
+        assert!(matches!(decoded["count"], SpanAttributeValue::Int(3)));
+    }
 }
@@ -0,0 +1,409 @@
+use std::collections::{BTreeMap, HashMap};
+
+use thiserror::Error;
+use time::OffsetDateTime;
+
+use super::TimeSeriesValue;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum FormulaError {
+    #[error("unexpected character '{0}' at position {1}")]
+    UnexpectedCharacter(char, usize),
+    #[error("invalid number '{0}'")]
+    InvalidNumber(String),
+    #[error("unexpected end of formula")]
+    UnexpectedEnd,
+    #[error("unexpected token '{0}'")]
+    UnexpectedToken(String),
+    #[error("unknown query '{0}'")]
+    UnknownQuery(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Variable(String),
+    Neg(Box<Expr>),
+    Binary(Box<Expr>, BinaryOp, Box<Expr>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    LParen,
+    RParen,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Ident(s) => write!(f, "{}", s),
+            Token::Plus => write!(f, "+"),
+            Token::Minus => write!(f, "-"),
+            Token::Star => write!(f, "*"),
+            Token::Slash => write!(f, "/"),
+            Token::LParen => write!(f, "("),
+            Token::RParen => write!(f, ")"),
+        }
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, FormulaError> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            ' ' | '\t' | '\n' | '\r' => {
+                i += 1;
+            }
+            '+' => {
+                tokens.push(Token::Plus);
+                i += 1;
+            }
+            '-' => {
+                tokens.push(Token::Minus);
+                i += 1;
+            }
+            '*' => {
+                tokens.push(Token::Star);
+                i += 1;
+            }
+            '/' => {
+                tokens.push(Token::Slash);
+                i += 1;
+            }
+            '(' => {
+                tokens.push(Token::LParen);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token::RParen);
+                i += 1;
+            }
+            c if c.is_ascii_digit() || c == '.' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                    i += 1;
+                }
+                let literal: String = chars[start..i].iter().collect();
+                let number = literal
+                    .parse::<f64>()
+                    .map_err(|_| FormulaError::InvalidNumber(literal.clone()))?;
+                tokens.push(Token::Number(number));
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                tokens.push(Token::Ident(chars[start..i].iter().collect()));
+            }
+            _ => return Err(FormulaError::UnexpectedCharacter(c, i)),
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    // expr := term (('+' | '-') term)*
+    fn expr(&mut self) -> Result<Expr, FormulaError> {
+        let mut lhs = self.term()?;
+
+        while let Some(op) = self.peek() {
+            let op = match op {
+                Token::Plus => BinaryOp::Add,
+                Token::Minus => BinaryOp::Sub,
+                _ => break,
+            };
+            self.next();
+            let rhs = self.term()?;
+            lhs = Expr::Binary(Box::new(lhs), op, Box::new(rhs));
+        }
+
+        Ok(lhs)
+    }
+
+    // term := unary (('*' | '/') unary)*
+    fn term(&mut self) -> Result<Expr, FormulaError> {
+        let mut lhs = self.unary()?;
+
+        while let Some(op) = self.peek() {
+            let op = match op {
+                Token::Star => BinaryOp::Mul,
+                Token::Slash => BinaryOp::Div,
+                _ => break,
+            };
+            self.next();
+            let rhs = self.unary()?;
+            lhs = Expr::Binary(Box::new(lhs), op, Box::new(rhs));
+        }
+
+        Ok(lhs)
+    }
+
+    // unary := '-' unary | primary
+    fn unary(&mut self) -> Result<Expr, FormulaError> {
+        if let Some(Token::Minus) = self.peek() {
+            self.next();
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+
+        self.primary()
+    }
+
+    // primary := number | identifier | '(' expr ')'
+    fn primary(&mut self) -> Result<Expr, FormulaError> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Ident(name)) => Ok(Expr::Variable(name)),
+            Some(Token::LParen) => {
+                let inner = self.expr()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(inner),
+                    Some(token) => Err(FormulaError::UnexpectedToken(token.to_string())),
+                    None => Err(FormulaError::UnexpectedEnd),
+                }
+            }
+            Some(token) => Err(FormulaError::UnexpectedToken(token.to_string())),
+            None => Err(FormulaError::UnexpectedEnd),
+        }
+    }
+}
+
+pub fn parse(input: &str) -> Result<Expr, FormulaError> {
+    let mut parser = Parser {
+        tokens: tokenize(input)?,
+        position: 0,
+    };
+
+    let expr = parser.expr()?;
+
+    match parser.next() {
+        None => Ok(expr),
+        Some(token) => Err(FormulaError::UnexpectedToken(token.to_string())),
+    }
+}
+
+/// Parses `input`, requiring every variable to name one of `queries`.
+pub fn parse_for_queries<V>(
+    input: &str,
+    queries: &HashMap<String, V>,
+) -> Result<Expr, FormulaError> {
+    let expr = parse(input)?;
+
+    if let Some(name) = expr
+        .variables()
+        .into_iter()
+        .find(|name| !queries.contains_key(*name))
+    {
+        return Err(FormulaError::UnknownQuery(name.to_string()));
+    }
+
+    Ok(expr)
+}
+
+/// Joins the named sub-query results on time bin and group and evaluates `expr` for
+/// each, dropping non-finite values.
+pub fn combine(
+    expr: &Expr,
+    results: impl IntoIterator<Item = (String, Vec<TimeSeriesValue>)>,
+) -> Vec<TimeSeriesValue> {
+    let mut values_by_bin: BTreeMap<(OffsetDateTime, Option<String>), HashMap<String, f64>> =
+        BTreeMap::new();
+
+    for (name, values) in results {
+        for v in values {
+            values_by_bin
+                .entry((v.end_time, v.group))
+                .or_default()
+                .insert(name.clone(), v.value);
+        }
+    }
+
+    values_by_bin
+        .into_iter()
+        .filter_map(|((end_time, group), vars)| {
+            let value = expr.evaluate(&vars);
+
+            value.is_finite().then_some(TimeSeriesValue {
+                end_time,
+                value,
+                group,
+            })
+        })
+        .collect()
+}
+
+impl Expr {
+    pub fn variables(&self) -> Vec<&str> {
+        match self {
+            Expr::Number(_) => Vec::new(),
+            Expr::Variable(name) => vec![name.as_str()],
+            Expr::Neg(inner) => inner.variables(),
+            Expr::Binary(lhs, _, rhs) => {
+                let mut vars = lhs.variables();
+                vars.extend(rhs.variables());
+                vars
+            }
+        }
+    }
+
+    /// Evaluates the expression, treating variables missing from `vars` as zero.
+    /// Division by zero yields a non-finite value, which callers are expected to drop.
+    pub fn evaluate(&self, vars: &HashMap<String, f64>) -> f64 {
+        match self {
+            Expr::Number(n) => *n,
+            Expr::Variable(name) => vars.get(name).copied().unwrap_or(0.0),
+            Expr::Neg(inner) => -inner.evaluate(vars),
+            Expr::Binary(lhs, op, rhs) => {
+                let lhs = lhs.evaluate(vars);
+                let rhs = rhs.evaluate(vars);
+                match op {
+                    BinaryOp::Add => lhs + rhs,
+                    BinaryOp::Sub => lhs - rhs,
+                    BinaryOp::Mul => lhs * rhs,
+                    BinaryOp::Div => lhs / rhs,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    fn vars(pairs: &[(&str, f64)]) -> HashMap<String, f64> {
+        pairs.iter().map(|(k, v)| (k.to_string(), *v)).collect()
+    }
+
+    fn value(group: &str, value: f64) -> TimeSeriesValue {
+        TimeSeriesValue {
+            end_time: datetime!(2026-10-18 12:00 UTC),
+            value,
+            group: Some(group.to_string()),
+        }
+    }
+
+    #[test]
+    fn multiplication_binds_tighter_than_addition() {
+        let vars = vars(&[("a", 2.0), ("b", 3.0), ("c", 4.0)]);
+
+        assert_eq!(parse("a + b * c").unwrap().evaluate(&vars), 14.0);
+        assert_eq!(parse("(a + b) * c").unwrap().evaluate(&vars), 20.0);
+        assert_eq!(parse("a - b - c").unwrap().evaluate(&vars), -5.0);
+        assert_eq!(parse("c / a / a").unwrap().evaluate(&vars), 1.0);
+        assert_eq!(parse("-a * b").unwrap().evaluate(&vars), -6.0);
+    }
+
+    #[test]
+    fn rejects_malformed_formulas() {
+        assert_eq!(parse("a +"), Err(FormulaError::UnexpectedEnd));
+        assert_eq!(parse("(a"), Err(FormulaError::UnexpectedEnd));
+        assert_eq!(
+            parse("a b"),
+            Err(FormulaError::UnexpectedToken("b".to_string()))
+        );
+        assert_eq!(
+            parse("a % b"),
+            Err(FormulaError::UnexpectedCharacter('%', 2))
+        );
+        assert_eq!(
+            parse("1.2.3"),
+            Err(FormulaError::InvalidNumber("1.2.3".to_string()))
+        );
+    }
+
+    #[test]
+    fn division_by_zero_is_not_finite() {
+        let expr = parse("a / b").unwrap();
+
+        assert!(
+            expr.evaluate(&vars(&[("a", 1.0), ("b", 0.0)]))
+                .is_infinite()
+        );
+        assert!(expr.evaluate(&vars(&[("a", 0.0), ("b", 0.0)])).is_nan());
+    }
+
+    #[test]
+    fn combine_drops_bins_divided_by_zero() {
+        let expr = parse("errors / total").unwrap();
+
+        let values = combine(
+            &expr,
+            [
+                ("errors".to_string(), vec![value("api", 1.0)]),
+                ("total".to_string(), vec![value("api", 0.0)]),
+            ],
+        );
+
+        assert!(values.is_empty());
+    }
+
+    #[test]
+    fn rejects_unknown_query_names() {
+        let queries = HashMap::from([("errors".to_string(), ()), ("total".to_string(), ())]);
+
+        assert!(parse_for_queries("errors / total", &queries).is_ok());
+        assert_eq!(
+            parse_for_queries("errors / totals", &queries),
+            Err(FormulaError::UnknownQuery("totals".to_string()))
+        );
+    }
+
+    #[test]
+    fn combine_treats_missing_group_values_as_zero() {
+        let expr = parse("a - b").unwrap();
+
+        let values = combine(
+            &expr,
+            [
+                ("a".to_string(), vec![value("api", 5.0), value("db", 2.0)]),
+                ("b".to_string(), vec![value("api", 1.0), value("web", 3.0)]),
+            ],
+        );
+
+        let by_group: Vec<(Option<&str>, f64)> = values
+            .iter()
+            .map(|v| (v.group.as_deref(), v.value))
+            .collect();
+        assert_eq!(
+            by_group,
+            vec![(Some("api"), 4.0), (Some("db"), 2.0), (Some("web"), -3.0)]
+        );
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use super::crud::WriteableSpan;
+use super::tail_sampling::trace_id_ratio;
+
+/// Services seen after this many are counted together under [`OTHER_SERVICES`], so
+/// exporters sending arbitrary service names can't grow per-service state unbounded.
+pub const MAX_TRACKED_SERVICES: usize = 1000;
+pub const OTHER_SERVICES: &str = "other";
+
+/// A tenant and one of its services.
+pub type ServiceKey = (String, Option<String>);
+
+/// Key to track `service_name` of `tenant_id` under in `services`, folding new
+/// services into the tenant's [`OTHER_SERVICES`] once [`MAX_TRACKED_SERVICES`] are
+/// tracked.
+pub fn service_key<V>(
+    services: &HashMap<ServiceKey, V>,
+    tenant_id: &str,
+    service_name: &Option<String>,
+) -> ServiceKey {
+    let key = (tenant_id.to_string(), service_name.clone());
+    if services.len() < MAX_TRACKED_SERVICES || services.contains_key(&key) {
+        key
+    } else {
+        (tenant_id.to_string(), Some(OTHER_SERVICES.to_string()))
+    }
+}
+
+/// Parses `service=value` pairs separated by commas, e.g. `checkout=0.1,search=0.5`.
+fn service_values_from_env<T: FromStr>(name: &str) -> HashMap<String, T> {
+    let Ok(value) = std::env::var(name) else {
+        return HashMap::new();
+    };
+
+    value
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let (service, value) = entry
+                .split_once('=')
+                .unwrap_or_else(|| panic!("{name} entries must look like service=value"));
+            let value = value
+                .trim()
+                .parse()
+                .unwrap_or_else(|_| panic!("{name} must contain valid numbers"));
+            (service.trim().to_string(), value)
+        })
+        .collect()
+}
+
+/// Sampling rates and rate limits applied to spans as they arrive. Per-service values
+/// replace the defaults for that `service_name`, and apply to each tenant's service
+/// separately.
+#[derive(Clone, Debug)]
+pub struct HeadSamplingConfig {
+    pub rate: f64,
+    pub rate_by_service: HashMap<String, f64>,
+    pub spans_per_second: Option<f64>,
+    pub spans_per_second_by_service: HashMap<String, f64>,
+}
+
+impl HeadSamplingConfig {
+    pub fn from_env() -> Self {
+        let rate = std::env::var("HEAD_SAMPLING_RATE")
+            .map(|v| {
+                v.parse()
+                    .expect("HEAD_SAMPLING_RATE must be a number between 0 and 1")
+            })
+            .unwrap_or(1.0);
+        let spans_per_second = std::env::var("SPANS_PER_SECOND_LIMIT").ok().map(|v| {
+            v.parse()
+                .expect("SPANS_PER_SECOND_LIMIT must be a valid number")
+        });
+
+        HeadSamplingConfig {
+            rate,
+            rate_by_service: service_values_from_env("HEAD_SAMPLING_RATE_BY_SERVICE"),
+            spans_per_second,
+            spans_per_second_by_service: service_values_from_env(
+                "SPANS_PER_SECOND_LIMIT_BY_SERVICE",
+            ),
+        }
+    }
+
+    fn rate(&self, service_name: Option<&str>) -> f64 {
+        service_name
+            .and_then(|s| self.rate_by_service.get(s))
+            .copied()
+            .unwrap_or(self.rate)
+    }
+
+    fn spans_per_second(&self, service_name: Option<&str>) -> Option<f64> {
+        service_name
+            .and_then(|s| self.spans_per_second_by_service.get(s))
+            .copied()
+            .or(self.spans_per_second)
+    }
+
+    fn is_configured(&self, service_name: Option<&str>) -> bool {
+        service_name.is_some_and(|s| {
+            self.rate_by_service.contains_key(s) || self.spans_per_second_by_service.contains_key(s)
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct IngestStats {
+    pub tenant_id: String,
+    pub service_name: Option<String>,
+    pub received_spans: u64,
+    /// Spans dropped by the service's sampling rate.
+    pub sampled_out_spans: u64,
+    /// Spans dropped for exceeding the service's spans-per-second limit.
+    pub rate_limited_spans: u64,
+}
+
+/// Token bucket holding up to one second's worth of spans.
+struct RateLimiter {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    fn take(&mut self, limit: f64, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit).min(limit);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Default)]
+struct ServiceState {
+    stats: IngestStats,
+    rate_limiter: Option<RateLimiter>,
+}
+
+pub struct HeadSampler {
+    config: HeadSamplingConfig,
+    services: Mutex<HashMap<ServiceKey, ServiceState>>,
+}
+
+impl HeadSampler {
+    pub fn new(config: HeadSamplingConfig) -> Self {
+        HeadSampler {
+            config,
+            services: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Keeps spans whose trace falls within their service's sampling rate, then drops
+    /// those over the rate limit of their tenant's service. Since the decision compares one hash of the
+    /// trace id against each rate, a trace kept by a service is also kept by every
+    /// service with a higher rate.
+    pub fn filter(&self, spans: Vec<WriteableSpan>) -> Vec<WriteableSpan> {
+        let now = Instant::now();
+        let mut services = self.services.lock().unwrap();
+
+        spans
+            .into_iter()
+            .filter(|span| {
+                let service_name = span.service_name.as_deref();
+                // Configured services keep their own rate limiter past the cap.
+                let key = if self.config.is_configured(service_name) {
+                    (span.tenant_id.clone(), span.service_name.clone())
+                } else {
+                    service_key(&services, &span.tenant_id, &span.service_name)
+                };
+                let state = services.entry(key).or_default();
+                state.stats.received_spans += 1;
+
+                if trace_id_ratio(&span.trace_id) >= self.config.rate(service_name) {
+                    state.stats.sampled_out_spans += 1;
+                    return false;
+                }
+
+                if let Some(limit) = self.config.spans_per_second(service_name) {
+                    let rate_limiter = state.rate_limiter.get_or_insert(RateLimiter {
+                        tokens: limit,
+                        last_refill: now,
+                    });
+                    if !rate_limiter.take(limit, now) {
+                        state.stats.rate_limited_spans += 1;
+                        return false;
+                    }
+                }
+
+                true
+            })
+            .collect()
+    }
+
+    pub fn stats(&self) -> Vec<IngestStats> {
+        let services = self.services.lock().unwrap();
+
+        let mut stats: Vec<IngestStats> = services
+            .iter()
+            .map(|((tenant_id, service_name), state)| IngestStats {
+                tenant_id: tenant_id.clone(),
+                service_name: service_name.clone(),
+                ..state.stats.clone()
+            })
+            .collect();
+        stats.sort_by(|a, b| (&a.tenant_id, &a.service_name).cmp(&(&b.tenant_id, &b.service_name)));
+
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(tenant_id: &str, service_name: &str) -> ServiceKey {
+        (tenant_id.to_string(), Some(service_name.to_string()))
+    }
+
+    #[test]
+    fn folds_services_past_the_cap_into_other() {
+        let mut services: HashMap<ServiceKey, ()> = (0..MAX_TRACKED_SERVICES)
+            .map(|i| (key("acme", &format!("service-{i}")), ()))
+            .collect();
+
+        let known = Some("service-1".to_string());
+        assert_eq!(
+            service_key(&services, "acme", &known),
+            key("acme", "service-1")
+        );
+        assert_eq!(
+            service_key(&services, "acme", &Some("new".to_string())),
+            key("acme", OTHER_SERVICES)
+        );
+        assert_eq!(
+            service_key(&services, "globex", &known),
+            key("globex", OTHER_SERVICES)
+        );
+
+        services.remove(&key("acme", "service-1"));
+        assert_eq!(
+            service_key(&services, "globex", &Some("new".to_string())),
+            key("globex", "new")
+        );
+    }
+
+    fn span(tenant_id: &str, trace_id: &str) -> WriteableSpan {
+        serde_json::from_value(serde_json::json!({
+            "tenant_id": tenant_id,
+            "span_id": trace_id,
+            "trace_id": trace_id,
+            "parent_span_id": null,
+            "operation_name": "op",
+            "start_time": "2026-10-18T12:00:00Z",
+            "end_time": "2026-10-18T12:00:01Z",
+            "duration_ns": 1_000_000_000,
+            "status_code": 0,
+            "status_message": null,
+            "span_kind": "Internal",
+            "instrumentation_library": null,
+            "service_name": "checkout",
+            "attributes": {},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn rate_limits_are_per_tenant() {
+        let sampler = HeadSampler::new(HeadSamplingConfig {
+            rate: 1.0,
+            rate_by_service: HashMap::new(),
+            spans_per_second: None,
+            spans_per_second_by_service: HashMap::from([("checkout".to_string(), 2.0)]),
+        });
+
+        let spans = ["a", "b", "c"]
+            .into_iter()
+            .flat_map(|trace_id| [span("acme", trace_id), span("globex", trace_id)])
+            .collect();
+        let kept = sampler.filter(spans);
+
+        assert_eq!(kept.iter().filter(|s| s.tenant_id == "acme").count(), 2);
+        assert_eq!(kept.iter().filter(|s| s.tenant_id == "globex").count(), 2);
+
+        let stats = sampler.stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].tenant_id, "acme");
+        assert_eq!(stats[0].rate_limited_spans, 1);
+        assert_eq!(stats[1].tenant_id, "globex");
+        assert_eq!(stats[1].rate_limited_spans, 1);
+    }
+}
@@ -0,0 +1,503 @@
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use super::crud::{
+    WriteableLog, WriteableLogAttribute, WriteableSpan, database_error, insert_log_attributes,
+    insert_logs, insert_spans, insert_traces, traces_from_spans,
+};
+use super::spool::{Spool, SpoolStats};
+
+#[derive(Clone, Debug)]
+pub struct IngestQueueConfig {
+    /// Requests are rejected while this many spans are queued.
+    pub max_spans: usize,
+    /// Requests are rejected while this many logs are queued.
+    pub max_logs: usize,
+    /// Number of spans or logs written per transaction.
+    pub batch_size: usize,
+    pub flush_interval: Duration,
+    /// Sent as `Retry-After` while the queue is full or the database is unavailable.
+    pub retry_after: Duration,
+}
+
+impl IngestQueueConfig {
+    pub fn from_env() -> Self {
+        let max_spans = std::env::var("INGEST_QUEUE_MAX_SPANS")
+            .map(|v| {
+                v.parse()
+                    .expect("INGEST_QUEUE_MAX_SPANS must be a valid number")
+            })
+            .unwrap_or(100_000);
+        let max_logs = std::env::var("INGEST_QUEUE_MAX_LOGS")
+            .map(|v| {
+                v.parse()
+                    .expect("INGEST_QUEUE_MAX_LOGS must be a valid number")
+            })
+            .unwrap_or(100_000);
+        let batch_size = std::env::var("INGEST_BATCH_SIZE")
+            .map(|v| v.parse().expect("INGEST_BATCH_SIZE must be a valid number"))
+            .unwrap_or(2000);
+        let flush_interval_ms = std::env::var("INGEST_FLUSH_INTERVAL_MS")
+            .map(|v| {
+                v.parse()
+                    .expect("INGEST_FLUSH_INTERVAL_MS must be a valid number")
+            })
+            .unwrap_or(1000);
+        let retry_after_secs = std::env::var("INGEST_RETRY_AFTER_SECS")
+            .map(|v| {
+                v.parse()
+                    .expect("INGEST_RETRY_AFTER_SECS must be a valid number")
+            })
+            .unwrap_or(5);
+
+        IngestQueueConfig {
+            max_spans,
+            max_logs,
+            batch_size,
+            flush_interval: Duration::from_millis(flush_interval_ms),
+            retry_after: Duration::from_secs(retry_after_secs),
+        }
+    }
+}
+
+/// One transaction's worth of queued data.
+#[derive(Serialize, Deserialize)]
+pub enum Batch {
+    Spans(Vec<WriteableSpan>),
+    Logs(Vec<WriteableLog>, Vec<WriteableLogAttribute>),
+}
+
+impl fmt::Display for Batch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Batch::Spans(spans) => write!(f, "{} spans", spans.len()),
+            Batch::Logs(logs, _) => write!(f, "{} logs", logs.len()),
+        }
+    }
+}
+
+impl Batch {
+    /// Splits the batch in two halves, keeping log attributes with their logs, or hands
+    /// it back when it holds a single span or log.
+    fn split(self) -> Result<(Batch, Batch), Batch> {
+        match self {
+            Batch::Spans(mut spans) if spans.len() > 1 => {
+                let second = spans.split_off(spans.len() / 2);
+                Ok((Batch::Spans(spans), Batch::Spans(second)))
+            }
+            Batch::Logs(mut logs, log_attributes) if logs.len() > 1 => {
+                let second = logs.split_off(logs.len() / 2);
+                let second_ids: HashSet<uuid::Uuid> = second.iter().map(|log| log.log_id).collect();
+                let (second_attributes, first_attributes) = log_attributes
+                    .into_iter()
+                    .partition(|attr| second_ids.contains(&attr.log_id));
+
+                Ok((
+                    Batch::Logs(logs, first_attributes),
+                    Batch::Logs(second, second_attributes),
+                ))
+            }
+            batch => Err(batch),
+        }
+    }
+}
+
+/// Spans and logs dropped because the database rejected them, since startup.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RejectedStats {
+    pub rejected_spans: u64,
+    pub rejected_logs: u64,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("ingest queue is full")]
+pub struct QueueFull;
+
+#[derive(Default)]
+struct Pending {
+    spans: VecDeque<WriteableSpan>,
+    /// Logs with their attributes, one entry per request.
+    logs: VecDeque<(Vec<WriteableLog>, Vec<WriteableLogAttribute>)>,
+    log_count: usize,
+}
+
+/// Collects spans and logs across requests and writes them in batches, so exporters
+/// don't wait on Postgres and small exports share transactions.
+pub struct IngestQueue {
+    config: IngestQueueConfig,
+    pending: Mutex<Pending>,
+    /// Wakes the writer once a full batch is queued.
+    batch_ready: tokio::sync::Notify,
+    /// Held while writing, so a final flush waits for the batch in flight.
+    writer: tokio::sync::Mutex<()>,
+    /// Cleared while batches can neither be written nor spooled, set again once the
+    /// queue drains.
+    accepting: AtomicBool,
+    spool: Option<Spool>,
+    rejected_spans: AtomicU64,
+    rejected_logs: AtomicU64,
+}
+
+impl IngestQueue {
+    pub fn new(config: IngestQueueConfig, spool: Option<Spool>) -> Self {
+        IngestQueue {
+            config,
+            pending: Mutex::new(Pending::default()),
+            batch_ready: tokio::sync::Notify::new(),
+            writer: tokio::sync::Mutex::new(()),
+            accepting: AtomicBool::new(true),
+            spool,
+            rejected_spans: AtomicU64::new(0),
+            rejected_logs: AtomicU64::new(0),
+        }
+    }
+
+    pub fn retry_after(&self) -> Duration {
+        self.config.retry_after
+    }
+
+    /// Whether exports are accepted, which stops while the database is unavailable and
+    /// the spool can't take more batches.
+    pub fn accepting(&self) -> bool {
+        self.accepting.load(Ordering::Relaxed)
+    }
+
+    /// Queues `spans`, or hands them back when the queue can't hold them.
+    pub fn try_push_spans(&self, spans: Vec<WriteableSpan>) -> Result<(), Vec<WriteableSpan>> {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.spans.len() + spans.len() > self.config.max_spans {
+            return Err(spans);
+        }
+
+        pending.spans.extend(spans);
+        self.notify_if_full(&pending);
+
+        Ok(())
+    }
+
+    /// How many more spans fit in the queue.
+    pub fn span_room(&self) -> usize {
+        let pending = self.pending.lock().unwrap();
+        self.config.max_spans.saturating_sub(pending.spans.len())
+    }
+
+    /// Queues spans regardless of capacity. Only for draining the tail sampler on
+    /// shutdown, when exports have stopped and the final flush writes everything.
+    pub fn push_spans(&self, spans: Vec<WriteableSpan>) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.spans.extend(spans);
+        self.notify_if_full(&pending);
+    }
+
+    pub fn try_push_logs(
+        &self,
+        logs: Vec<WriteableLog>,
+        log_attributes: Vec<WriteableLogAttribute>,
+    ) -> Result<(), QueueFull> {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.log_count + logs.len() > self.config.max_logs {
+            return Err(QueueFull);
+        }
+
+        pending.log_count += logs.len();
+        pending.logs.push_back((logs, log_attributes));
+        self.notify_if_full(&pending);
+
+        Ok(())
+    }
+
+    fn notify_if_full(&self, pending: &Pending) {
+        if pending.spans.len() >= self.config.batch_size
+            || pending.log_count >= self.config.batch_size
+        {
+            self.batch_ready.notify_one();
+        }
+    }
+
+    /// Takes up to a batch of spans and a batch of whole requests' worth of logs.
+    fn take_batches(&self) -> Vec<Batch> {
+        let mut pending = self.pending.lock().unwrap();
+        let mut batches = Vec::new();
+
+        let count = pending.spans.len().min(self.config.batch_size);
+        if count > 0 {
+            batches.push(Batch::Spans(pending.spans.drain(..count).collect()));
+        }
+
+        let mut logs = Vec::new();
+        let mut log_attributes = Vec::new();
+        while logs.len() < self.config.batch_size {
+            let Some((request_logs, request_attributes)) = pending.logs.pop_front() else {
+                break;
+            };
+            pending.log_count -= request_logs.len();
+            logs.extend(request_logs);
+            log_attributes.extend(request_attributes);
+        }
+        if !logs.is_empty() {
+            batches.push(Batch::Logs(logs, log_attributes));
+        }
+
+        batches
+    }
+
+    /// Puts a batch that couldn't be written back at the front of the queue.
+    fn requeue(&self, batch: Batch) {
+        let mut pending = self.pending.lock().unwrap();
+        match batch {
+            Batch::Spans(spans) => {
+                for span in spans.into_iter().rev() {
+                    pending.spans.push_front(span);
+                }
+            }
+            Batch::Logs(logs, log_attributes) => {
+                pending.log_count += logs.len();
+                pending.logs.push_front((logs, log_attributes));
+            }
+        }
+    }
+
+    pub fn rejected_stats(&self) -> RejectedStats {
+        RejectedStats {
+            rejected_spans: self.rejected_spans.load(Ordering::Relaxed),
+            rejected_logs: self.rejected_logs.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Writes `batch`, halving the parts the database rejects until the spans or logs
+    /// it rejects are singled out, which are dropped. When the database becomes
+    /// unavailable, the parts not written yet are handed back in order.
+    async fn write_or_reject(
+        &self,
+        pool: &PgPool,
+        batch: Batch,
+    ) -> Result<(), (tonic::Status, Vec<Batch>)> {
+        // The next part to write is at the end.
+        let mut parts = vec![batch];
+
+        while let Some(part) = parts.pop() {
+            match write_batch(pool, &part).await {
+                Ok(()) => {}
+                Err(e) if e.code() == tonic::Code::Unavailable => {
+                    parts.push(part);
+                    parts.reverse();
+                    return Err((e, parts));
+                }
+                Err(e) => match part.split() {
+                    Ok((first, second)) => {
+                        parts.push(second);
+                        parts.push(first);
+                    }
+                    Err(part) => {
+                        tracing::error!("Dropping {} the database rejected: {}", part, e);
+                        match part {
+                            Batch::Spans(_) => self.rejected_spans.fetch_add(1, Ordering::Relaxed),
+                            Batch::Logs(..) => self.rejected_logs.fetch_add(1, Ordering::Relaxed),
+                        };
+                    }
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn spool_stats(&self) -> Option<SpoolStats> {
+        self.spool.as_ref().map(|spool| spool.stats())
+    }
+
+    /// Writes spooled batches, oldest first, and returns whether the spool was emptied.
+    async fn replay_spool(&self, pool: &PgPool) -> bool {
+        let Some(spool) = &self.spool else {
+            return true;
+        };
+
+        while let Some((sequence, batch)) = spool.oldest().await {
+            match batch {
+                Ok(batch) => {
+                    // Parts written before the database went away are written again
+                    // on the next replay, where their spans are skipped as duplicates
+                    // and their logs rejected as such.
+                    if self.write_or_reject(pool, batch).await.is_err() {
+                        return false;
+                    }
+                }
+                Err(e) => tracing::error!("Dropping spooled batch {}: {}", sequence, e),
+            }
+
+            if let Err(e) = spool.remove(sequence).await {
+                tracing::error!("Removing spooled batch {} failed: {}", sequence, e);
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Replays the spool, then writes everything queued so far, one batch at a time.
+    /// While the database is unavailable, batches are spooled, or kept queued once the
+    /// spool is full or disabled. Spans and logs the database rejects are dropped.
+    pub async fn flush(&self, pool: &PgPool) {
+        let _writer = self.writer.lock().await;
+
+        let mut database_available = self.replay_spool(pool).await;
+
+        loop {
+            let batches = self.take_batches();
+            if batches.is_empty() {
+                self.accepting.store(true, Ordering::Relaxed);
+                return;
+            }
+
+            let mut unwritten = Vec::new();
+            for batch in batches {
+                if !database_available {
+                    unwritten.push(batch);
+                    continue;
+                }
+
+                if let Err((e, parts)) = self.write_or_reject(pool, batch).await {
+                    tracing::warn!("Database unavailable: {}", e);
+                    database_available = false;
+                    unwritten.extend(parts);
+                }
+            }
+
+            let mut batches = unwritten.into_iter();
+            while let Some(batch) = batches.next() {
+                let spooled = match &self.spool {
+                    Some(spool) => match spool.append(&batch).await {
+                        Ok(()) => true,
+                        Err(e) => {
+                            tracing::warn!("Spooling {} failed: {}", batch, e);
+                            false
+                        }
+                    },
+                    None => false,
+                };
+
+                if !spooled {
+                    tracing::warn!("Keeping {} queued until the database is available", batch);
+                    // Requeued at the front, so newest first to keep them in order.
+                    let mut batches: Vec<Batch> = std::iter::once(batch).chain(batches).collect();
+                    while let Some(batch) = batches.pop() {
+                        self.requeue(batch);
+                    }
+                    self.accepting.store(false, Ordering::Relaxed);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Flushes whenever a batch fills up or `flush_interval` passes, until the process
+    /// exits.
+    pub async fn run(&self, pool: &PgPool) {
+        loop {
+            tokio::select! {
+                _ = self.batch_ready.notified() => {}
+                _ = tokio::time::sleep(self.config.flush_interval) => {}
+            }
+
+            self.flush(pool).await;
+        }
+    }
+}
+
+async fn write_batch(pool: &PgPool, batch: &Batch) -> Result<(), tonic::Status> {
+    let mut tx = pool.begin().await.map_err(database_error)?;
+
+    match batch {
+        Batch::Spans(spans) => {
+            // Traces only count newly inserted spans, so a retried export isn't counted
+            // twice. The trace foreign key is deferred until commit.
+            let inserted = insert_spans(spans, &mut tx).await?;
+            insert_traces(&traces_from_spans(&inserted), &mut tx).await?;
+        }
+        Batch::Logs(logs, log_attributes) => {
+            insert_logs(logs, &mut tx).await?;
+            insert_log_attributes(log_attributes, &mut tx).await?;
+        }
+    }
+
+    tx.commit().await.map_err(database_error)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(id: u128) -> WriteableLog {
+        serde_json::from_value(serde_json::json!({
+            "log_id": uuid::Uuid::from_u128(id),
+            "trace_id": null,
+            "span_id": null,
+            "timestamp": "2026-10-18T12:00:00Z",
+            "observed_timestamp": null,
+            "severity_number": 9,
+            "severity_text": null,
+            "body": null,
+            "instrumentation_library": null,
+            "service_name": null,
+        }))
+        .unwrap()
+    }
+
+    fn attribute(id: u128) -> WriteableLogAttribute {
+        serde_json::from_value(serde_json::json!({
+            "log_id": uuid::Uuid::from_u128(id),
+            "key": "k",
+            "value": "v",
+            "timestamp": [2026, 291, 12, 0, 0, 0, 0, 0, 0],
+        }))
+        .unwrap()
+    }
+
+    fn ids(logs: &[WriteableLog], attributes: &[WriteableLogAttribute]) -> (Vec<u128>, Vec<u128>) {
+        (
+            logs.iter().map(|log| log.log_id.as_u128()).collect(),
+            attributes
+                .iter()
+                .map(|attr| attr.log_id.as_u128())
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn splitting_keeps_log_attributes_with_their_logs() {
+        let batch = Batch::Logs(
+            (1..=3).map(log).collect(),
+            vec![attribute(3), attribute(1), attribute(2), attribute(3)],
+        );
+
+        let Ok((Batch::Logs(logs, attributes), Batch::Logs(second, second_attributes))) =
+            batch.split()
+        else {
+            panic!("expected two log batches");
+        };
+
+        assert_eq!(ids(&logs, &attributes), (vec![1], vec![1]));
+        assert_eq!(
+            ids(&second, &second_attributes),
+            (vec![2, 3], vec![3, 2, 3])
+        );
+    }
+
+    #[test]
+    fn single_rows_are_not_split() {
+        assert!(
+            Batch::Logs(vec![log(1)], vec![attribute(1)])
+                .split()
+                .is_err()
+        );
+        assert!(Batch::Spans(Vec::new()).split().is_err());
+    }
+}
@@ -0,0 +1,130 @@
+use std::time::Duration;
+
+use axum::http::{HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use prost::Message;
+
+/// `google.rpc.Status`, the body OTLP/HTTP clients expect on errors. `details` is left
+/// out since we never send any.
+#[derive(Clone, PartialEq, Message)]
+struct RpcStatus {
+    #[prost(int32, tag = "1")]
+    code: i32,
+    #[prost(string, tag = "2")]
+    message: String,
+}
+
+/// An OTLP/HTTP error response. Clients retry 429 and 503 after `Retry-After` and drop
+/// the data on any other status.
+#[derive(Debug)]
+pub struct OtlpError {
+    status: StatusCode,
+    code: tonic::Code,
+    message: String,
+    retry_after: Option<Duration>,
+}
+
+impl OtlpError {
+    pub fn invalid_argument(message: impl Into<String>) -> Self {
+        OtlpError {
+            status: StatusCode::BAD_REQUEST,
+            code: tonic::Code::InvalidArgument,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    pub fn unsupported_media_type(content_type: &str) -> Self {
+        OtlpError {
+            status: StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            code: tonic::Code::InvalidArgument,
+            message: format!(
+                "Unsupported content type {content_type:?}, expected application/x-protobuf"
+            ),
+            retry_after: None,
+        }
+    }
+
+    pub fn unauthenticated(message: impl Into<String>) -> Self {
+        OtlpError {
+            status: StatusCode::UNAUTHORIZED,
+            code: tonic::Code::Unauthenticated,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    pub fn permission_denied(message: impl Into<String>) -> Self {
+        OtlpError {
+            status: StatusCode::FORBIDDEN,
+            code: tonic::Code::PermissionDenied,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        OtlpError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            code: tonic::Code::Internal,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    /// The ingest queue is full.
+    pub fn resource_exhausted(retry_after: Duration) -> Self {
+        OtlpError {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            code: tonic::Code::ResourceExhausted,
+            message: "Ingest queue is full".to_string(),
+            retry_after: Some(retry_after),
+        }
+    }
+
+    /// The tenant sent more than its quota allows.
+    pub fn quota_exceeded(tenant: &str) -> Self {
+        OtlpError {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            code: tonic::Code::ResourceExhausted,
+            message: format!("Tenant {tenant:?} is over its ingest quota"),
+            retry_after: Some(Duration::from_secs(1)),
+        }
+    }
+
+    /// The database can't be reached, so nothing is being written.
+    pub fn unavailable(retry_after: Duration) -> Self {
+        OtlpError {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            code: tonic::Code::Unavailable,
+            message: "Database is unavailable".to_string(),
+            retry_after: Some(retry_after),
+        }
+    }
+}
+
+impl IntoResponse for OtlpError {
+    fn into_response(self) -> Response {
+        let body = RpcStatus {
+            code: self.code as i32,
+            message: self.message,
+        };
+
+        let mut response = (
+            self.status,
+            [(header::CONTENT_TYPE, "application/x-protobuf")],
+            body.encode_to_vec(),
+        )
+            .into_response();
+
+        if let Some(retry_after) = self.retry_after {
+            // Retry-After takes whole seconds.
+            let seconds = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+        }
+
+        response
+    }
+}
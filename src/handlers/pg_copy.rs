@@ -0,0 +1,179 @@
+use serde::Serialize;
+use sqlx::{Postgres, Transaction};
+use time::OffsetDateTime;
+use time::macros::datetime;
+
+/// Postgres stores timestamps as microseconds since this instant.
+const PG_EPOCH: OffsetDateTime = datetime!(2000-01-01 0:00 UTC);
+
+const SIGNATURE: &[u8] = b"PGCOPY\n\xff\r\n\0";
+
+/// Encodes rows in Postgres' binary `COPY` format. Every row must start with
+/// [`CopyEncoder::row`] and then write exactly that many fields, in column order.
+pub struct CopyEncoder {
+    buf: Vec<u8>,
+}
+
+impl CopyEncoder {
+    pub fn new() -> Self {
+        let mut buf = Vec::with_capacity(64 * 1024);
+        buf.extend_from_slice(SIGNATURE);
+        buf.extend_from_slice(&0_i32.to_be_bytes()); // Flags
+        buf.extend_from_slice(&0_i32.to_be_bytes()); // Header extension length
+
+        CopyEncoder { buf }
+    }
+
+    pub fn row(&mut self, field_count: i16) -> &mut Self {
+        self.buf.extend_from_slice(&field_count.to_be_bytes());
+        self
+    }
+
+    fn field(&mut self, bytes: &[u8]) -> &mut Self {
+        self.buf
+            .extend_from_slice(&(bytes.len() as i32).to_be_bytes());
+        self.buf.extend_from_slice(bytes);
+        self
+    }
+
+    pub fn null(&mut self) -> &mut Self {
+        self.buf.extend_from_slice(&(-1_i32).to_be_bytes());
+        self
+    }
+
+    /// `TEXT`, `VARCHAR` and enum labels.
+    pub fn text(&mut self, value: &str) -> &mut Self {
+        self.field(value.as_bytes())
+    }
+
+    pub fn optional_text(&mut self, value: Option<&str>) -> &mut Self {
+        match value {
+            Some(value) => self.text(value),
+            None => self.null(),
+        }
+    }
+
+    pub fn int4(&mut self, value: i32) -> &mut Self {
+        self.field(&value.to_be_bytes())
+    }
+
+    pub fn int8(&mut self, value: i64) -> &mut Self {
+        self.field(&value.to_be_bytes())
+    }
+
+    pub fn timestamptz(&mut self, value: OffsetDateTime) -> &mut Self {
+        let micros = (value - PG_EPOCH).whole_microseconds() as i64;
+        self.int8(micros)
+    }
+
+    pub fn optional_timestamptz(&mut self, value: Option<OffsetDateTime>) -> &mut Self {
+        match value {
+            Some(value) => self.timestamptz(value),
+            None => self.null(),
+        }
+    }
+
+    pub fn uuid(&mut self, value: uuid::Uuid) -> &mut Self {
+        self.field(value.as_bytes())
+    }
+
+    pub fn jsonb<T: Serialize>(&mut self, value: &T) -> &mut Self {
+        // JSONB's binary format is a version byte followed by the JSON text.
+        let mut bytes = vec![1_u8];
+        serde_json::to_writer(&mut bytes, value).expect("values serialize to JSON");
+        self.field(&bytes)
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.buf.extend_from_slice(&(-1_i16).to_be_bytes());
+        self.buf
+    }
+}
+
+/// Runs `statement`, a `COPY ... FROM STDIN (FORMAT BINARY)`, with the encoded rows.
+pub async fn copy_in(
+    statement: &str,
+    encoder: CopyEncoder,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<u64, sqlx::Error> {
+    let mut copy = tx.copy_in_raw(statement).await?;
+    copy.send(encoder.finish()).await?;
+    copy.finish().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER_LENGTH: usize = SIGNATURE.len() + 8;
+
+    fn rows(encoder: CopyEncoder) -> Vec<u8> {
+        let buf = encoder.finish();
+        assert_eq!(&buf[..SIGNATURE.len()], SIGNATURE);
+        assert_eq!(&buf[SIGNATURE.len()..HEADER_LENGTH], &[0; 8]);
+        assert_eq!(&buf[buf.len() - 2..], &[0xff, 0xff]);
+
+        buf[HEADER_LENGTH..buf.len() - 2].to_vec()
+    }
+
+    #[test]
+    fn empty_copy_is_header_and_trailer() {
+        assert!(rows(CopyEncoder::new()).is_empty());
+    }
+
+    #[test]
+    fn encodes_fields_with_their_length() {
+        let mut encoder = CopyEncoder::new();
+        encoder
+            .row(5)
+            .text("ab")
+            .null()
+            .int4(-2)
+            .int8(1)
+            .optional_text(None);
+
+        let mut expected = vec![0, 5];
+        expected.extend([0, 0, 0, 2, b'a', b'b']);
+        expected.extend([0xff, 0xff, 0xff, 0xff]);
+        expected.extend([0, 0, 0, 4, 0xff, 0xff, 0xff, 0xfe]);
+        expected.extend([0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0, 1]);
+        expected.extend([0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(rows(encoder), expected);
+    }
+
+    #[test]
+    fn timestamps_count_microseconds_from_2000() {
+        let mut encoder = CopyEncoder::new();
+        encoder
+            .row(2)
+            .timestamptz(datetime!(2000-01-01 0:00:01.000002 UTC))
+            .timestamptz(datetime!(1999-12-31 23:59:59 UTC));
+
+        let mut expected = vec![0, 2, 0, 0, 0, 8];
+        expected.extend(1_000_002_i64.to_be_bytes());
+        expected.extend([0, 0, 0, 8]);
+        expected.extend((-1_000_000_i64).to_be_bytes());
+        assert_eq!(rows(encoder), expected);
+    }
+
+    #[test]
+    fn jsonb_is_versioned_json_text() {
+        let mut encoder = CopyEncoder::new();
+        encoder.row(1).jsonb(&serde_json::json!({"a": 1}));
+
+        let mut expected = vec![0, 1, 0, 0, 0, 8, 1];
+        expected.extend(br#"{"a":1}"#);
+        assert_eq!(rows(encoder), expected);
+    }
+
+    #[test]
+    fn uuids_are_raw_bytes() {
+        let id = uuid::Uuid::from_u128(0x0102030405060708090a0b0c0d0e0f10);
+        let mut encoder = CopyEncoder::new();
+        encoder.row(1).uuid(id);
+
+        let mut expected = vec![0, 1, 0, 0, 0, 16];
+        expected.extend(1..=16);
+        assert_eq!(rows(encoder), expected);
+    }
+}
@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+use serde::Deserialize;
+
+use super::crud::{SpanAttributeValue, WriteableLog, WriteableSpan};
+use super::redaction::{attribute_value_string, key_regex};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Processor {
+    /// Moves the `from` attribute to `to`, replacing any value already there.
+    Rename { from: String, to: String },
+    /// Copies resource attributes matching `keys` onto each span or log, without
+    /// replacing attributes it already has.
+    CopyResourceAttributes { keys: Vec<String> },
+    /// Sets the service name, when the resource has none, from the first of `from`
+    /// found in the attributes or the resource, or else `default`.
+    DeriveServiceName {
+        from: Vec<String>,
+        default: Option<String>,
+    },
+    /// Replaces literals in a SQL statement with `?`, so equal queries group together.
+    NormalizeDbStatement {
+        #[serde(default = "default_db_statement_key")]
+        key: String,
+    },
+    /// Drops spans whose name and attributes all match. Doesn't apply to logs.
+    DropSpans {
+        name: Option<String>,
+        #[serde(default)]
+        attributes: HashMap<String, String>,
+    },
+}
+
+fn default_db_statement_key() -> String {
+    "db.statement".to_string()
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ProcessorRule {
+    /// Limits the processor to spans and logs from this service.
+    pub service: Option<String>,
+    #[serde(flatten)]
+    pub processor: Processor,
+}
+
+/// Processors applied, in order, to spans and logs before they're redacted, validated
+/// and sampled.
+#[derive(Clone, Debug, Default)]
+pub struct ProcessorConfig {
+    pub rules: Vec<ProcessorRule>,
+}
+
+impl ProcessorConfig {
+    pub fn from_env() -> Self {
+        let rules = std::env::var("ATTRIBUTE_PROCESSORS")
+            .map(|v| {
+                serde_json::from_str(&v)
+                    .expect("ATTRIBUTE_PROCESSORS must be a JSON list of processors")
+            })
+            .unwrap_or_default();
+
+        ProcessorConfig { rules }
+    }
+}
+
+/// Lets the same processors work on typed span attributes and string log attributes.
+trait AttributeValue: Clone {
+    fn as_str(&self) -> Option<&str>;
+    fn string(value: String) -> Self;
+}
+
+impl AttributeValue for SpanAttributeValue {
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            SpanAttributeValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn string(value: String) -> Self {
+        SpanAttributeValue::String(value)
+    }
+}
+
+impl AttributeValue for String {
+    fn as_str(&self) -> Option<&str> {
+        Some(self)
+    }
+
+    fn string(value: String) -> Self {
+        value
+    }
+}
+
+/// Rewrites SQL statements into a parameterized form.
+struct SqlNormalizer {
+    string_literal: Regex,
+    number: Regex,
+    list: Regex,
+}
+
+impl SqlNormalizer {
+    fn new() -> Self {
+        SqlNormalizer {
+            string_literal: Regex::new(r"'(?:[^']|'')*'").unwrap(),
+            // Skips digits in identifiers and `$1` placeholders.
+            number: Regex::new(r"(^|[^\w$])\d+(?:\.\d+)?\b").unwrap(),
+            // `IN (?, ?, ?)` becomes `IN (?)` whatever the list's length.
+            list: Regex::new(r"\(\s*\?(?:\s*,\s*\?)+\s*\)").unwrap(),
+        }
+    }
+
+    fn normalize(&self, statement: &str) -> String {
+        let statement = self.string_literal.replace_all(statement, "?");
+        let statement = self.number.replace_all(&statement, "${1}?");
+        self.list.replace_all(&statement, "(?)").into_owned()
+    }
+}
+
+enum Step {
+    Rename(String, String),
+    CopyResourceAttributes(Vec<Regex>),
+    DeriveServiceName(Vec<String>, Option<String>),
+    NormalizeDbStatement(String),
+    DropSpans(Option<Regex>, Vec<(String, Regex)>),
+}
+
+struct Rule {
+    service: Option<String>,
+    step: Step,
+}
+
+impl Rule {
+    fn matches(&self, service_name: Option<&str>) -> bool {
+        self.service
+            .as_deref()
+            .is_none_or(|service| Some(service) == service_name)
+    }
+}
+
+pub struct Processors {
+    rules: Vec<Rule>,
+    sql: SqlNormalizer,
+}
+
+impl Processors {
+    pub fn new(config: ProcessorConfig) -> Self {
+        let rules = config
+            .rules
+            .into_iter()
+            .map(|rule| Rule {
+                service: rule.service,
+                step: match rule.processor {
+                    Processor::Rename { from, to } => Step::Rename(from, to),
+                    Processor::CopyResourceAttributes { keys } => Step::CopyResourceAttributes(
+                        keys.iter().map(|key| key_regex(key)).collect(),
+                    ),
+                    Processor::DeriveServiceName { from, default } => {
+                        Step::DeriveServiceName(from, default)
+                    }
+                    Processor::NormalizeDbStatement { key } => Step::NormalizeDbStatement(key),
+                    Processor::DropSpans { name, attributes } => Step::DropSpans(
+                        name.as_deref().map(key_regex),
+                        attributes
+                            .into_iter()
+                            .map(|(key, value)| (key, key_regex(&value)))
+                            .collect(),
+                    ),
+                },
+            })
+            .collect();
+
+        Processors {
+            rules,
+            sql: SqlNormalizer::new(),
+        }
+    }
+
+    /// Applies every attribute processor, and returns the service name, which
+    /// `DeriveServiceName` may have set.
+    fn process<V: AttributeValue>(
+        &self,
+        mut service_name: Option<String>,
+        attributes: &mut HashMap<String, V>,
+        resource_attributes: &HashMap<String, V>,
+    ) -> Option<String> {
+        for rule in &self.rules {
+            if !rule.matches(service_name.as_deref()) {
+                continue;
+            }
+
+            match &rule.step {
+                Step::Rename(from, to) => {
+                    if let Some(value) = attributes.remove(from) {
+                        attributes.insert(to.clone(), value);
+                    }
+                }
+                Step::CopyResourceAttributes(keys) => {
+                    for (key, value) in resource_attributes {
+                        if keys.iter().any(|pattern| pattern.is_match(key)) {
+                            attributes
+                                .entry(key.clone())
+                                .or_insert_with(|| value.clone());
+                        }
+                    }
+                }
+                Step::DeriveServiceName(from, default) => {
+                    if service_name.is_none() {
+                        service_name = from
+                            .iter()
+                            .find_map(|key| {
+                                attributes
+                                    .get(key)
+                                    .or_else(|| resource_attributes.get(key))
+                                    .and_then(|value| value.as_str())
+                                    .map(str::to_string)
+                            })
+                            .or_else(|| default.clone());
+                    }
+                }
+                Step::NormalizeDbStatement(key) => {
+                    if let Some(value) = attributes.get_mut(key)
+                        && let Some(statement) = value.as_str()
+                    {
+                        *value = V::string(self.sql.normalize(statement));
+                    }
+                }
+                Step::DropSpans(..) => {}
+            }
+        }
+
+        service_name
+    }
+
+    fn dropped(&self, span: &WriteableSpan) -> bool {
+        self.rules.iter().any(|rule| {
+            let Step::DropSpans(name, attributes) = &rule.step else {
+                return false;
+            };
+
+            rule.matches(span.service_name.as_deref())
+                && name
+                    .as_ref()
+                    .is_none_or(|name| name.is_match(&span.operation_name))
+                && attributes.iter().all(|(key, value)| {
+                    span.attributes
+                        .get(key)
+                        .is_some_and(|v| value.is_match(&attribute_value_string(v)))
+                })
+        })
+    }
+
+    /// Processes `span`, returning `false` when it should be dropped.
+    pub fn process_span(
+        &self,
+        span: &mut WriteableSpan,
+        resource_attributes: &HashMap<String, SpanAttributeValue>,
+    ) -> bool {
+        span.service_name = self.process(
+            span.service_name.take(),
+            &mut span.attributes,
+            resource_attributes,
+        );
+
+        !self.dropped(span)
+    }
+
+    pub fn process_log(
+        &self,
+        log: &mut WriteableLog,
+        attributes: &mut HashMap<String, String>,
+        resource_attributes: &HashMap<String, String>,
+    ) {
+        log.service_name = self.process(log.service_name.take(), attributes, resource_attributes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(statement: &str) -> String {
+        SqlNormalizer::new().normalize(statement)
+    }
+
+    #[test]
+    fn replaces_string_and_number_literals() {
+        assert_eq!(
+            normalize("SELECT * FROM users WHERE id = 42 AND name = 'O''Brien'"),
+            "SELECT * FROM users WHERE id = ? AND name = ?"
+        );
+        assert_eq!(
+            normalize("UPDATE t SET ratio = 1.5, note = 'row 7' WHERE x >= -3"),
+            "UPDATE t SET ratio = ?, note = ? WHERE x >= -?"
+        );
+        assert_eq!(normalize("42"), "?");
+    }
+
+    #[test]
+    fn keeps_identifiers_and_placeholders() {
+        assert_eq!(
+            normalize("SELECT col1, t2.a FROM t2 WHERE id = $1 LIMIT 10"),
+            "SELECT col1, t2.a FROM t2 WHERE id = $1 LIMIT ?"
+        );
+    }
+
+    #[test]
+    fn collapses_lists_of_any_length() {
+        assert_eq!(
+            normalize("SELECT * FROM t WHERE a IN (1, 2, 3) AND b IN ('x','y')"),
+            "SELECT * FROM t WHERE a IN (?) AND b IN (?)"
+        );
+        assert_eq!(
+            normalize("SELECT * FROM t WHERE a IN (7)"),
+            "SELECT * FROM t WHERE a IN (?)"
+        );
+        assert_eq!(
+            normalize("INSERT INTO t (a, b) VALUES (1, 'x')"),
+            "INSERT INTO t (a, b) VALUES (?)"
+        );
+    }
+}
@@ -0,0 +1,347 @@
+use std::collections::HashMap;
+
+use hmac::{Hmac, Mac};
+use regex::Regex;
+use serde::Deserialize;
+use sha2::Sha256;
+
+use super::crud::SpanAttributeValue;
+
+/// Log bodies are matched against rules as if they were an attribute with this key.
+const LOG_BODY_KEY: &str = "body";
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum RedactionAction {
+    /// Removes the attribute.
+    Drop,
+    /// Replaces the value with its HMAC-SHA256 under the hash key, so equal values still
+    /// group together but can't be recovered by hashing guesses.
+    Hash,
+    /// Replaces every match of `pattern` in the value.
+    Mask {
+        pattern: String,
+        #[serde(default = "default_mask_replacement")]
+        replacement: String,
+    },
+}
+
+fn default_mask_replacement() -> String {
+    "[REDACTED]".to_string()
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RedactionRule {
+    /// Attribute keys the rule applies to, where `*` matches any characters.
+    pub key: String,
+    /// Limits the rule to spans and logs from this service.
+    pub service: Option<String>,
+    #[serde(flatten)]
+    pub action: RedactionAction,
+}
+
+/// Rules applied, in order, to span attributes and log attributes and bodies before
+/// they're stored.
+#[derive(Clone, Debug, Default)]
+pub struct RedactionConfig {
+    pub rules: Vec<RedactionRule>,
+    /// Secret for `hash` rules, required when there are any.
+    pub hash_key: Option<String>,
+}
+
+impl RedactionConfig {
+    pub fn from_env() -> Self {
+        let rules = std::env::var("REDACTION_RULES")
+            .map(|v| {
+                serde_json::from_str(&v).expect("REDACTION_RULES must be a JSON list of rules")
+            })
+            .unwrap_or_default();
+        let hash_key = std::env::var("REDACTION_HASH_KEY").ok();
+
+        RedactionConfig { rules, hash_key }
+    }
+}
+
+enum Action {
+    Drop,
+    Hash(Hmac<Sha256>),
+    Mask(Regex, String),
+}
+
+struct Rule {
+    key: Regex,
+    service: Option<String>,
+    action: Action,
+}
+
+impl Rule {
+    fn matches(&self, service_name: Option<&str>, key: &str) -> bool {
+        self.service
+            .as_deref()
+            .is_none_or(|service| Some(service) == service_name)
+            && self.key.is_match(key)
+    }
+}
+
+/// Turns a key pattern like `http.*` into an anchored regex.
+pub fn key_regex(pattern: &str) -> Regex {
+    let pattern = pattern
+        .split('*')
+        .map(regex::escape)
+        .collect::<Vec<_>>()
+        .join(".*");
+
+    Regex::new(&format!("^{pattern}$")).expect("escaped key patterns are valid regexes")
+}
+
+pub fn attribute_value_string(value: &SpanAttributeValue) -> String {
+    match value {
+        SpanAttributeValue::String(s) => s.clone(),
+        SpanAttributeValue::Int(i) => i.to_string(),
+        SpanAttributeValue::Float(f) => f.to_string(),
+        SpanAttributeValue::Bool(b) => b.to_string(),
+    }
+}
+
+pub struct Redactor {
+    rules: Vec<Rule>,
+}
+
+impl Redactor {
+    pub fn new(config: RedactionConfig) -> Self {
+        let hash_key = config.hash_key.filter(|key| !key.is_empty());
+        let rules = config
+            .rules
+            .into_iter()
+            .map(|rule| Rule {
+                key: key_regex(&rule.key),
+                service: rule.service,
+                action: match rule.action {
+                    RedactionAction::Drop => Action::Drop,
+                    RedactionAction::Hash => {
+                        let key = hash_key.as_ref().expect(
+                            "REDACTION_HASH_KEY must be set when REDACTION_RULES has a hash rule",
+                        );
+                        Action::Hash(
+                            Hmac::new_from_slice(key.as_bytes())
+                                .expect("HMAC takes keys of any length"),
+                        )
+                    }
+                    RedactionAction::Mask {
+                        pattern,
+                        replacement,
+                    } => Action::Mask(
+                        Regex::new(&pattern).unwrap_or_else(|e| {
+                            panic!("REDACTION_RULES has an invalid pattern {pattern:?}: {e}")
+                        }),
+                        replacement,
+                    ),
+                },
+            })
+            .collect();
+
+        Redactor { rules }
+    }
+
+    fn applies(&self, service_name: Option<&str>, key: &str) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| rule.matches(service_name, key))
+    }
+
+    /// Applies the matching rules to `value`, returning `None` when it's dropped.
+    fn redact(&self, service_name: Option<&str>, key: &str, mut value: String) -> Option<String> {
+        for rule in &self.rules {
+            if !rule.matches(service_name, key) {
+                continue;
+            }
+
+            match &rule.action {
+                Action::Drop => return None,
+                Action::Hash(mac) => {
+                    value = hex::encode(
+                        mac.clone()
+                            .chain_update(value.as_bytes())
+                            .finalize()
+                            .into_bytes(),
+                    )
+                }
+                Action::Mask(pattern, replacement) => {
+                    value = pattern
+                        .replace_all(&value, replacement.as_str())
+                        .into_owned()
+                }
+            }
+        }
+
+        Some(value)
+    }
+
+    pub fn redact_span_attributes(
+        &self,
+        service_name: Option<&str>,
+        attributes: &mut HashMap<String, SpanAttributeValue>,
+    ) {
+        attributes.retain(|key, value| {
+            if !self.applies(service_name, key) {
+                return true;
+            }
+
+            let original = attribute_value_string(value);
+            match self.redact(service_name, key, original.clone()) {
+                None => false,
+                Some(redacted) => {
+                    // Untouched values keep their type.
+                    if redacted != original {
+                        *value = SpanAttributeValue::String(redacted);
+                    }
+                    true
+                }
+            }
+        });
+    }
+
+    pub fn redact_log_attributes(
+        &self,
+        service_name: Option<&str>,
+        attributes: HashMap<String, String>,
+    ) -> HashMap<String, String> {
+        attributes
+            .into_iter()
+            .filter_map(|(key, value)| {
+                let value = self.redact(service_name, &key, value)?;
+                Some((key, value))
+            })
+            .collect()
+    }
+
+    pub fn redact_log_body(
+        &self,
+        service_name: Option<&str>,
+        body: Option<String>,
+    ) -> Option<String> {
+        self.redact(service_name, LOG_BODY_KEY, body?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redactor(rules: serde_json::Value) -> Redactor {
+        Redactor::new(RedactionConfig {
+            rules: serde_json::from_value(rules).unwrap(),
+            hash_key: Some("Jefe".to_string()),
+        })
+    }
+
+    #[test]
+    fn key_patterns_match_whole_keys() {
+        let http = key_regex("http.*");
+        assert!(http.is_match("http.url"));
+        assert!(http.is_match("http."));
+        assert!(!http.is_match("xhttp.url"));
+        assert!(!http.is_match("httpXurl"));
+
+        let card = key_regex("*card*");
+        assert!(card.is_match("card"));
+        assert!(card.is_match("user.card.number"));
+        assert!(!card.is_match("cart"));
+
+        let exact = key_regex("user.id");
+        assert!(exact.is_match("user.id"));
+        assert!(!exact.is_match("user.ids"));
+    }
+
+    #[test]
+    fn drops_masks_and_hashes_in_order() {
+        let redactor = redactor(serde_json::json!([
+            { "key": "card*", "action": "drop" },
+            { "key": "http.url", "action": "mask", "pattern": "token=[^&]+", "replacement": "token=?" },
+            { "key": "user.*", "action": "mask", "pattern": "@.*", "replacement": "" },
+            { "key": "user.*", "action": "hash" },
+        ]));
+
+        let attributes = HashMap::from([
+            ("card.number".to_string(), "4111".to_string()),
+            ("http.url".to_string(), "/a?token=s3cret&x=1".to_string()),
+            (
+                "user.email".to_string(),
+                "what do ya want for nothing?@example.com".to_string(),
+            ),
+            ("other".to_string(), "kept".to_string()),
+        ]);
+
+        let redacted = redactor.redact_log_attributes(None, attributes);
+
+        assert!(!redacted.contains_key("card.number"));
+        assert_eq!(redacted["http.url"], "/a?token=?&x=1");
+        // HMAC-SHA256 of the masked value, RFC 4231 test case 2.
+        assert_eq!(
+            redacted["user.email"],
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(redacted["other"], "kept");
+    }
+
+    #[test]
+    fn rules_can_be_limited_to_a_service() {
+        let redactor = redactor(serde_json::json!([
+            { "key": "db.statement", "service": "checkout", "action": "drop" },
+        ]));
+
+        let mut checkout = HashMap::from([(
+            "db.statement".to_string(),
+            SpanAttributeValue::String("SELECT 1".to_string()),
+        )]);
+        redactor.redact_span_attributes(Some("checkout"), &mut checkout);
+        assert!(checkout.is_empty());
+
+        let mut search = HashMap::from([(
+            "db.statement".to_string(),
+            SpanAttributeValue::String("SELECT 1".to_string()),
+        )]);
+        redactor.redact_span_attributes(Some("search"), &mut search);
+        assert_eq!(search.len(), 1);
+    }
+
+    #[test]
+    fn span_values_keep_their_type_unless_changed() {
+        let redactor = redactor(serde_json::json!([
+            { "key": "count", "action": "mask", "pattern": "secret" },
+            { "key": "id", "action": "hash" },
+        ]));
+
+        let mut attributes = HashMap::from([
+            ("count".to_string(), SpanAttributeValue::Int(3)),
+            ("id".to_string(), SpanAttributeValue::Int(42)),
+        ]);
+        redactor.redact_span_attributes(None, &mut attributes);
+
+        assert!(matches!(attributes["count"], SpanAttributeValue::Int(3)));
+        assert!(matches!(&attributes["id"], SpanAttributeValue::String(s) if s.len() == 64));
+    }
+
+    #[test]
+    fn log_bodies_match_as_body() {
+        let redactor = redactor(serde_json::json!([
+            { "key": "body", "action": "mask", "pattern": "\\d{4}" },
+        ]));
+
+        assert_eq!(
+            redactor.redact_log_body(None, Some("pin 1234".to_string())),
+            Some("pin [REDACTED]".to_string())
+        );
+        assert_eq!(redactor.redact_log_body(None, None), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "REDACTION_HASH_KEY must be set")]
+    fn hash_rules_require_a_key() {
+        Redactor::new(RedactionConfig {
+            rules: serde_json::from_value(serde_json::json!([{ "key": "id", "action": "hash" }]))
+                .unwrap(),
+            hash_key: None,
+        });
+    }
+}
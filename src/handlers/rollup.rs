@@ -0,0 +1,159 @@
+use sqlx::{Postgres, QueryBuilder};
+use time::OffsetDateTime;
+
+use super::{
+    AggregateSource, AggregateType, DEFAULT_TIME_BIN, Filter, HeatmapSpec, QuerySpec, TimeBin,
+    TimeBinQuery, push_filters, push_tenant_and_time_range, time_bin_to_sql,
+};
+
+/// Span columns that are kept as dimensions of `span_rollup_1m`.
+const ROLLUP_COLUMNS: [&str; 3] = ["service_name", "operation_name", "kind"];
+
+fn is_rollup_time_bin(time_bin: &TimeBinQuery) -> bool {
+    !matches!(time_bin.bin, TimeBin::Second) && time_bin.value > 0
+}
+
+/// Rollup buckets can only be cut at whole minutes.
+fn is_rollup_bound(bound: Option<OffsetDateTime>) -> bool {
+    bound.is_none_or(|t| t.second() == 0 && t.nanosecond() == 0)
+}
+
+fn is_rollup_filter(filter: &Filter) -> bool {
+    ROLLUP_COLUMNS.contains(&filter.column.as_str())
+}
+
+fn is_error_filter(filter: &Filter) -> bool {
+    filter.column == "status_code" && filter.value == "2"
+}
+
+fn group_to_sql(col: &str) -> String {
+    match col {
+        "service_name" => "NULLIF(service_name, '')::TEXT AS group, ".to_string(),
+        _ => format!("{col}::TEXT AS group, "),
+    }
+}
+
+/// Rewrites `params` against the per-minute rollups, or returns `None` when the query
+/// needs data the rollups don't keep (second-level bins, attributes, other columns).
+pub fn build_rollup_query<'a>(
+    params: &'a QuerySpec,
+    tenant: &'a str,
+) -> Option<QueryBuilder<'a, Postgres>> {
+    let time_bin = params.time_bin.as_ref().unwrap_or(&DEFAULT_TIME_BIN);
+    if !is_rollup_time_bin(time_bin)
+        || params.aggregate.source != AggregateSource::SpanColumn
+        || !is_rollup_bound(params.start)
+        || !is_rollup_bound(params.end)
+    {
+        return None;
+    }
+
+    if let Some(col) = &params.group
+        && !ROLLUP_COLUMNS.contains(&col.as_str())
+    {
+        return None;
+    }
+
+    let filters = params.filters.as_deref().unwrap_or_default();
+    let errors_only = filters.iter().any(is_error_filter);
+    let dimension_filters: Vec<Filter> = filters
+        .iter()
+        .filter(|f| !is_error_filter(f))
+        .cloned()
+        .collect();
+
+    if !dimension_filters.iter().all(is_rollup_filter) {
+        return None;
+    }
+
+    let value_sql = match &params.aggregate.agg_type {
+        AggregateType::Count if errors_only => "SUM(error_count)::DOUBLE PRECISION AS value",
+        AggregateType::Count => "SUM(span_count)::DOUBLE PRECISION AS value",
+        _ if errors_only => return None,
+        AggregateType::Sum(col) if col == "duration_ns" => {
+            "SUM(duration_sum_ns)::DOUBLE PRECISION AS value"
+        }
+        AggregateType::Avg(col) if col == "duration_ns" => {
+            "(SUM(duration_sum_ns)::DOUBLE PRECISION / SUM(span_count)) AS value"
+        }
+        AggregateType::Min(col) if col == "duration_ns" => {
+            "MIN(duration_min_ns)::DOUBLE PRECISION AS value"
+        }
+        AggregateType::Max(col) if col == "duration_ns" => {
+            "MAX(duration_max_ns)::DOUBLE PRECISION AS value"
+        }
+        _ => return None,
+    };
+
+    let mut builder: QueryBuilder<Postgres> = QueryBuilder::new("SELECT ");
+    builder.push(time_bin_to_sql(time_bin, "bucket").as_str());
+
+    if let Some(col) = &params.group {
+        builder.push(format!("\n{}", group_to_sql(col)));
+    }
+
+    builder.push(format!("\n{value_sql}"));
+    builder.push("\nFROM span_rollup_1m ");
+
+    push_tenant_and_time_range(&mut builder, tenant, "bucket", params.start, params.end);
+
+    if !dimension_filters.is_empty() {
+        builder.push(" AND ");
+        push_filters(&mut builder, &dimension_filters);
+    }
+
+    builder.push("\nGROUP BY time_bin");
+    if let Some(col) = &params.group {
+        builder.push(format!(", {}", col));
+    }
+
+    if errors_only {
+        // Match the raw query, which has no row for bins without errors.
+        builder.push("\nHAVING SUM(error_count) > 0");
+    }
+
+    builder.push("\nORDER BY time_bin");
+    if let Some(col) = &params.group {
+        builder.push(format!(", {}", col));
+    }
+
+    Some(builder)
+}
+
+/// Reads heatmap buckets from the rollup histograms, or returns `None` when `params`
+/// can't be answered from them.
+pub fn build_rollup_heatmap_query<'a>(
+    params: &'a HeatmapSpec,
+    tenant: &'a str,
+) -> Option<QueryBuilder<'a, Postgres>> {
+    let time_bin = params.time_bin.as_ref().unwrap_or(&DEFAULT_TIME_BIN);
+    if !is_rollup_time_bin(time_bin)
+        || !is_rollup_bound(params.start)
+        || !is_rollup_bound(params.end)
+    {
+        return None;
+    }
+
+    let filters = params.filters.as_deref().unwrap_or_default();
+    if !filters.iter().all(is_rollup_filter) {
+        return None;
+    }
+
+    let mut builder: QueryBuilder<Postgres> = QueryBuilder::new("SELECT ");
+    builder.push(time_bin_to_sql(time_bin, "bucket").as_str());
+    builder.push("\n(h.i - 1)::INTEGER AS bucket,\nSUM(h.n)::BIGINT AS count");
+    builder.push("\nFROM span_rollup_1m, UNNEST(duration_histogram) WITH ORDINALITY AS h(n, i) ");
+
+    push_tenant_and_time_range(&mut builder, tenant, "bucket", params.start, params.end);
+
+    if !filters.is_empty() {
+        builder.push(" AND ");
+        push_filters(&mut builder, filters);
+    }
+
+    builder.push("\nGROUP BY time_bin, h.i");
+    builder.push("\nHAVING SUM(h.n) > 0");
+    builder.push("\nORDER BY time_bin, h.i");
+
+    Some(builder)
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use super::crud::{SpanAttributeValue, WriteableSpan};
+use super::head_sampling::{ServiceKey, service_key};
+
+/// Appended to values cut short by [`SpanValidator`].
+const TRUNCATION_MARKER: &str = "…[truncated]";
+
+/// Limits enforced on incoming spans.
+#[derive(Clone, Debug)]
+pub struct SpanValidationConfig {
+    /// Attributes beyond this many are dropped, keeping the first keys alphabetically.
+    pub max_attributes: usize,
+    /// Longer string attribute values and status messages are truncated, in bytes.
+    pub max_attribute_value_length: usize,
+    /// Longer span names are truncated, in bytes.
+    pub max_name_length: usize,
+}
+
+impl SpanValidationConfig {
+    pub fn from_env() -> Self {
+        let max_attributes = std::env::var("SPAN_MAX_ATTRIBUTES")
+            .map(|v| {
+                v.parse()
+                    .expect("SPAN_MAX_ATTRIBUTES must be a valid number")
+            })
+            .unwrap_or(128);
+        let max_attribute_value_length = std::env::var("SPAN_MAX_ATTRIBUTE_VALUE_LENGTH")
+            .map(|v| {
+                v.parse()
+                    .expect("SPAN_MAX_ATTRIBUTE_VALUE_LENGTH must be a valid number")
+            })
+            .unwrap_or(4096);
+        let max_name_length = std::env::var("SPAN_MAX_NAME_LENGTH")
+            .map(|v| {
+                v.parse()
+                    .expect("SPAN_MAX_NAME_LENGTH must be a valid number")
+            })
+            .unwrap_or(1024);
+
+        SpanValidationConfig {
+            max_attributes,
+            max_attribute_value_length,
+            max_name_length,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ValidationStats {
+    pub tenant_id: String,
+    pub service_name: Option<String>,
+    /// Spans rejected for having no start time.
+    pub rejected_spans: u64,
+    /// Spans whose end time was before their start and was moved to it.
+    pub clamped_end_times: u64,
+    pub dropped_attributes: u64,
+    /// Span names, attribute values and status messages cut to the length limit.
+    pub truncated_values: u64,
+}
+
+/// Truncates `value` to at most `max_length` bytes, marker included, on a character
+/// boundary. Limits too short to hold the marker truncate without it. Returns whether
+/// it was truncated.
+fn truncate(value: &mut String, max_length: usize) -> bool {
+    if value.len() <= max_length {
+        return false;
+    }
+
+    let marker = if max_length >= TRUNCATION_MARKER.len() {
+        TRUNCATION_MARKER
+    } else {
+        ""
+    };
+    let mut end = max_length - marker.len();
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    value.truncate(end);
+    value.push_str(marker);
+
+    true
+}
+
+/// Rejects spans that can't be stored meaningfully and corrects the rest to fit the
+/// configured limits, counting every change per tenant and service.
+pub struct SpanValidator {
+    config: SpanValidationConfig,
+    stats: Mutex<HashMap<ServiceKey, ValidationStats>>,
+}
+
+impl SpanValidator {
+    pub fn new(config: SpanValidationConfig) -> Self {
+        SpanValidator {
+            config,
+            stats: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the spans to keep, corrected, and an error for each rejected span.
+    pub fn validate(&self, spans: Vec<WriteableSpan>) -> (Vec<WriteableSpan>, Vec<tonic::Status>) {
+        let mut stats = self.stats.lock().unwrap();
+        let mut valid = Vec::with_capacity(spans.len());
+        let mut errors = Vec::new();
+
+        for mut span in spans {
+            let key = service_key(&stats, &span.tenant_id, &span.service_name);
+            let stats = stats.entry(key).or_default();
+
+            // A zero timestamp would be stored as 1970.
+            if span.start_time == OffsetDateTime::UNIX_EPOCH {
+                stats.rejected_spans += 1;
+                errors.push(tonic::Status::invalid_argument(format!(
+                    "Span {} has no start time",
+                    span.span_id
+                )));
+                continue;
+            }
+
+            if span.end_time < span.start_time {
+                span.end_time = span.start_time;
+                span.duration_ns = 0;
+                stats.clamped_end_times += 1;
+            }
+
+            if truncate(&mut span.operation_name, self.config.max_name_length) {
+                stats.truncated_values += 1;
+            }
+            if let Some(status_message) = &mut span.status_message
+                && truncate(status_message, self.config.max_attribute_value_length)
+            {
+                stats.truncated_values += 1;
+            }
+
+            if span.attributes.len() > self.config.max_attributes {
+                let mut keys: Vec<String> = span.attributes.keys().cloned().collect();
+                keys.sort();
+                for key in &keys[self.config.max_attributes..] {
+                    span.attributes.remove(key);
+                }
+                stats.dropped_attributes += (keys.len() - self.config.max_attributes) as u64;
+            }
+            for value in span.attributes.values_mut() {
+                if let SpanAttributeValue::String(value) = value
+                    && truncate(value, self.config.max_attribute_value_length)
+                {
+                    stats.truncated_values += 1;
+                }
+            }
+
+            valid.push(span);
+        }
+
+        (valid, errors)
+    }
+
+    pub fn stats(&self) -> Vec<ValidationStats> {
+        let stats = self.stats.lock().unwrap();
+
+        let mut stats: Vec<ValidationStats> = stats
+            .iter()
+            .map(|((tenant_id, service_name), stats)| ValidationStats {
+                tenant_id: tenant_id.clone(),
+                service_name: service_name.clone(),
+                ..stats.clone()
+            })
+            .collect();
+        stats.sort_by(|a, b| (&a.tenant_id, &a.service_name).cmp(&(&b.tenant_id, &b.service_name)));
+
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncates_with_the_marker_on_a_char_boundary() {
+        let mut value = "é".repeat(20);
+        assert!(truncate(&mut value, 20));
+        assert!(value.len() <= 20);
+        assert_eq!(value, format!("{}{}", "é".repeat(3), TRUNCATION_MARKER));
+
+        let mut short = "short".to_string();
+        assert!(!truncate(&mut short, 20));
+        assert_eq!(short, "short");
+    }
+
+    #[test]
+    fn truncates_without_the_marker_below_its_length() {
+        let mut value = "abcdefghij".to_string();
+        assert!(truncate(&mut value, 4));
+        assert_eq!(value, "abcd");
+
+        let mut value = "abcdefghij".to_string();
+        assert!(truncate(&mut value, 0));
+        assert_eq!(value, "");
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::VecDeque;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use super::ingest_queue::Batch;
+
+#[derive(Clone, Debug)]
+pub struct SpoolConfig {
+    /// Directory batches are spooled to while the database is unavailable. Spooling is
+    /// disabled when unset.
+    pub dir: Option<PathBuf>,
+    /// Batches are kept in memory, and then rejected, once the spool reaches this size.
+    pub max_bytes: u64,
+}
+
+impl SpoolConfig {
+    pub fn from_env() -> Self {
+        let dir = std::env::var("INGEST_SPOOL_DIR").ok().map(PathBuf::from);
+        let max_bytes = std::env::var("INGEST_SPOOL_MAX_BYTES")
+            .map(|v| {
+                v.parse()
+                    .expect("INGEST_SPOOL_MAX_BYTES must be a valid number")
+            })
+            .unwrap_or(1024 * 1024 * 1024);
+
+        SpoolConfig { dir, max_bytes }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpoolStats {
+    pub batches: usize,
+    pub bytes: u64,
+    pub max_bytes: u64,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub oldest_batch_spooled_at: Option<OffsetDateTime>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SpoolError {
+    #[error("spool is full")]
+    Full,
+    #[error("spool I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid spooled batch: {0}")]
+    Decode(#[from] serde_json::Error),
+}
+
+struct SpooledBatch {
+    sequence: u64,
+    bytes: u64,
+    spooled_at: OffsetDateTime,
+}
+
+#[derive(Default)]
+struct SpoolState {
+    next_sequence: u64,
+    /// Spooled batches, oldest first.
+    batches: VecDeque<SpooledBatch>,
+    bytes: u64,
+}
+
+/// Batches written to disk, one file each, while the database is unavailable, so they
+/// survive restarts and are replayed in the order they were spooled.
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    state: Mutex<SpoolState>,
+}
+
+impl Spool {
+    /// Opens `dir`, picking up batches spooled before a restart.
+    pub fn open(dir: &Path, max_bytes: u64) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+
+        let mut state = SpoolState::default();
+        let mut batches = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            match path.extension().and_then(|e| e.to_str()) {
+                // Left behind by a crash halfway through an append.
+                Some("tmp") => std::fs::remove_file(&path)?,
+                Some("json") => {
+                    let Some(sequence) = path
+                        .file_stem()
+                        .and_then(|s| s.to_str())
+                        .and_then(|s| s.parse().ok())
+                    else {
+                        continue;
+                    };
+                    let metadata = entry.metadata()?;
+                    batches.push(SpooledBatch {
+                        sequence,
+                        bytes: metadata.len(),
+                        spooled_at: metadata.modified()?.into(),
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        batches.sort_by_key(|batch| batch.sequence);
+        state.next_sequence = batches.last().map_or(0, |batch| batch.sequence + 1);
+        state.bytes = batches.iter().map(|batch| batch.bytes).sum();
+        state.batches = batches.into();
+
+        if !state.batches.is_empty() {
+            tracing::info!(
+                "Found {} spooled batches ({} bytes) to replay",
+                state.batches.len(),
+                state.bytes
+            );
+        }
+
+        Ok(Spool {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            state: Mutex::new(state),
+        })
+    }
+
+    fn path(&self, sequence: u64, extension: &str) -> PathBuf {
+        self.dir.join(format!("{sequence:020}.{extension}"))
+    }
+
+    /// Writes `batch` after every batch spooled so far. The file is synced and renamed
+    /// into place, so a crash never leaves a partial batch behind.
+    pub async fn append(&self, batch: &Batch) -> Result<(), SpoolError> {
+        let contents = serde_json::to_vec(batch)?;
+        let bytes = contents.len() as u64;
+
+        let sequence = {
+            let mut state = self.state.lock().unwrap();
+            if state.bytes + bytes > self.max_bytes {
+                return Err(SpoolError::Full);
+            }
+            state.next_sequence += 1;
+            state.next_sequence - 1
+        };
+
+        let tmp_path = self.path(sequence, "tmp");
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        tokio::io::AsyncWriteExt::write_all(&mut file, &contents).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp_path, self.path(sequence, "json")).await?;
+
+        let mut state = self.state.lock().unwrap();
+        state.bytes += bytes;
+        state.batches.push_back(SpooledBatch {
+            sequence,
+            bytes,
+            spooled_at: OffsetDateTime::now_utc(),
+        });
+
+        Ok(())
+    }
+
+    /// Reads the oldest spooled batch, returning its sequence number for [`Spool::remove`].
+    pub async fn oldest(&self) -> Option<(u64, Result<Batch, SpoolError>)> {
+        let sequence = self.state.lock().unwrap().batches.front()?.sequence;
+
+        let batch = match tokio::fs::read(self.path(sequence, "json")).await {
+            Ok(contents) => serde_json::from_slice(&contents).map_err(SpoolError::from),
+            Err(e) => Err(e.into()),
+        };
+
+        Some((sequence, batch))
+    }
+
+    /// Deletes the oldest batch once it's been replayed.
+    pub async fn remove(&self, sequence: u64) -> Result<(), SpoolError> {
+        tokio::fs::remove_file(self.path(sequence, "json")).await?;
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(batch) = state.batches.pop_front() {
+            debug_assert_eq!(batch.sequence, sequence);
+            state.bytes -= batch.bytes;
+        }
+
+        Ok(())
+    }
+
+    pub fn stats(&self) -> SpoolStats {
+        let state = self.state.lock().unwrap();
+
+        SpoolStats {
+            batches: state.batches.len(),
+            bytes: state.bytes,
+            max_bytes: self.max_bytes,
+            oldest_batch_spooled_at: state.batches.front().map(|batch| batch.spooled_at),
+        }
+    }
+}
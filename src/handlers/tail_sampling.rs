@@ -0,0 +1,278 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::crud::{SpanAttributeValue, WriteableSpan};
+use super::ingest_queue::{IngestQueue, QueueFull};
+
+/// How long a decision is remembered, so spans arriving after it follow the rest of
+/// their trace.
+const DECIDED_TRACE_TTL: Duration = Duration::from_secs(300);
+
+/// Policies deciding which buffered traces are kept. A trace is kept when any policy
+/// matches.
+#[derive(Clone, Debug)]
+pub struct TailSamplingConfig {
+    pub enabled: bool,
+    /// How long spans of a trace are buffered, counted from its first span.
+    pub decision_wait: Duration,
+    /// Exports are rejected while this many spans are held, and the oldest traces are
+    /// decided early to make room.
+    pub max_buffered_spans: usize,
+    pub keep_errors: bool,
+    pub latency_threshold: Option<Duration>,
+    /// Keeps traces with a span whose attribute `key` has the given value.
+    pub attributes: Vec<(String, String)>,
+    /// Fraction of the remaining traces kept as a baseline.
+    pub probability: f64,
+}
+
+impl TailSamplingConfig {
+    pub fn from_env() -> Self {
+        let enabled = std::env::var("TAIL_SAMPLING_ENABLED")
+            .map(|v| {
+                v.parse()
+                    .expect("TAIL_SAMPLING_ENABLED must be true or false")
+            })
+            .unwrap_or(false);
+        let decision_wait_secs = std::env::var("TAIL_SAMPLING_DECISION_WAIT_SECS")
+            .map(|v| {
+                v.parse()
+                    .expect("TAIL_SAMPLING_DECISION_WAIT_SECS must be a valid number")
+            })
+            .unwrap_or(10);
+        let max_buffered_spans = std::env::var("TAIL_SAMPLING_MAX_BUFFERED_SPANS")
+            .map(|v| {
+                v.parse()
+                    .expect("TAIL_SAMPLING_MAX_BUFFERED_SPANS must be a valid number")
+            })
+            .unwrap_or(100_000);
+        let keep_errors = std::env::var("TAIL_SAMPLING_KEEP_ERRORS")
+            .map(|v| {
+                v.parse()
+                    .expect("TAIL_SAMPLING_KEEP_ERRORS must be true or false")
+            })
+            .unwrap_or(true);
+        let latency_threshold = std::env::var("TAIL_SAMPLING_LATENCY_THRESHOLD_MS")
+            .ok()
+            .map(|v| {
+                Duration::from_millis(
+                    v.parse()
+                        .expect("TAIL_SAMPLING_LATENCY_THRESHOLD_MS must be a valid number"),
+                )
+            });
+        let attributes = std::env::var("TAIL_SAMPLING_ATTRIBUTES")
+            .map(|v| {
+                v.split(',')
+                    .filter(|entry| !entry.trim().is_empty())
+                    .map(|entry| {
+                        let (key, value) = entry
+                            .split_once('=')
+                            .expect("TAIL_SAMPLING_ATTRIBUTES entries must look like key=value");
+                        (key.trim().to_string(), value.trim().to_string())
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let probability = std::env::var("TAIL_SAMPLING_PROBABILITY")
+            .map(|v| {
+                v.parse()
+                    .expect("TAIL_SAMPLING_PROBABILITY must be a number between 0 and 1")
+            })
+            .unwrap_or(0.0);
+
+        TailSamplingConfig {
+            enabled,
+            decision_wait: Duration::from_secs(decision_wait_secs),
+            max_buffered_spans,
+            keep_errors,
+            latency_threshold,
+            attributes,
+            probability,
+        }
+    }
+
+    fn keeps(&self, spans: &[WriteableSpan]) -> bool {
+        if self.keep_errors && spans.iter().any(|span| span.status_code == 2) {
+            return true;
+        }
+
+        if let Some(threshold) = self.latency_threshold {
+            let start = spans.iter().map(|span| span.start_time).min();
+            let end = spans.iter().map(|span| span.end_time).max();
+            if let (Some(start), Some(end)) = (start, end)
+                && end - start >= threshold
+            {
+                return true;
+            }
+        }
+
+        if self.attributes.iter().any(|(key, value)| {
+            spans.iter().any(|span| {
+                span.attributes
+                    .get(key)
+                    .is_some_and(|v| attribute_matches(v, value))
+            })
+        }) {
+            return true;
+        }
+
+        spans
+            .first()
+            .is_some_and(|span| trace_id_ratio(&span.trace_id) < self.probability)
+    }
+}
+
+fn attribute_matches(attribute: &SpanAttributeValue, value: &str) -> bool {
+    match attribute {
+        SpanAttributeValue::String(s) => s == value,
+        SpanAttributeValue::Int(i) => i.to_string() == value,
+        SpanAttributeValue::Float(f) => f.to_string() == value,
+        SpanAttributeValue::Bool(b) => b.to_string() == value,
+    }
+}
+
+/// Maps a hex trace id to `[0, 1)` using its low 64 bits, which are random for W3C
+/// trace ids, so every span of a trace gets the same value.
+pub fn trace_id_ratio(trace_id: &str) -> f64 {
+    let low_bits = &trace_id[trace_id.len().saturating_sub(16)..];
+    let value = u64::from_str_radix(low_bits, 16).unwrap_or(0);
+
+    value as f64 / (u64::MAX as f64 + 1.0)
+}
+
+/// Traces are told apart by tenant as well, since tenants may reuse trace ids.
+type TraceKey = (String, String);
+
+fn trace_key(span: &WriteableSpan) -> TraceKey {
+    (span.tenant_id.clone(), span.trace_id.clone())
+}
+
+struct PendingTrace {
+    first_seen: Instant,
+    spans: Vec<WriteableSpan>,
+}
+
+#[derive(Default)]
+struct Buffer {
+    pending: HashMap<TraceKey, PendingTrace>,
+    /// Pending traces in the order they were first seen.
+    arrival_order: VecDeque<TraceKey>,
+    buffered_spans: usize,
+    decided: HashMap<TraceKey, (bool, Instant)>,
+    /// Spans of kept traces waiting to be written.
+    ready: Vec<WriteableSpan>,
+}
+
+/// Buffers spans per trace for `decision_wait` and only writes the traces that
+/// `TailSamplingConfig` keeps.
+pub struct TailSampler {
+    config: TailSamplingConfig,
+    buffer: Mutex<Buffer>,
+}
+
+impl TailSampler {
+    pub fn new(config: TailSamplingConfig) -> Self {
+        TailSampler {
+            config,
+            buffer: Mutex::new(Buffer::default()),
+        }
+    }
+
+    /// Buffers `spans`, or rejects all of them when the buffer can't hold them.
+    pub fn add(&self, spans: Vec<WriteableSpan>) -> Result<(), QueueFull> {
+        let now = Instant::now();
+        let mut buffer = self.buffer.lock().unwrap();
+
+        // Kept spans waiting to be queued count too, so a full ingest queue pushes back
+        // on exporters.
+        if buffer.buffered_spans + buffer.ready.len() + spans.len() > self.config.max_buffered_spans
+        {
+            return Err(QueueFull);
+        }
+
+        for span in spans {
+            let key = trace_key(&span);
+            match buffer.decided.get(&key) {
+                Some((true, _)) => buffer.ready.push(span),
+                Some((false, _)) => {}
+                None => {
+                    buffer.buffered_spans += 1;
+                    match buffer.pending.get_mut(&key) {
+                        Some(trace) => trace.spans.push(span),
+                        None => {
+                            buffer.arrival_order.push_back(key.clone());
+                            buffer.pending.insert(
+                                key,
+                                PendingTrace {
+                                    first_seen: now,
+                                    spans: vec![span],
+                                },
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decides every trace whose window has passed, or the oldest ones while the buffer
+    /// is full, and returns up to `max_spans` spans to write. With `decide_all`, every
+    /// buffered trace is decided right away.
+    fn take_decided(&self, decide_all: bool, max_spans: usize) -> Vec<WriteableSpan> {
+        let now = Instant::now();
+        let mut buffer = self.buffer.lock().unwrap();
+
+        buffer
+            .decided
+            .retain(|_, (_, decided_at)| now.duration_since(*decided_at) < DECIDED_TRACE_TTL);
+
+        while let Some(key) = buffer.arrival_order.front() {
+            let trace = &buffer.pending[key];
+            if !decide_all
+                && now.duration_since(trace.first_seen) < self.config.decision_wait
+                && buffer.buffered_spans < self.config.max_buffered_spans
+            {
+                break;
+            }
+
+            let key = buffer.arrival_order.pop_front().unwrap();
+            let trace = buffer.pending.remove(&key).unwrap();
+            buffer.buffered_spans -= trace.spans.len();
+
+            let keep = self.config.keeps(&trace.spans);
+            if keep {
+                buffer.ready.extend(trace.spans);
+            }
+            buffer.decided.insert(key, (keep, now));
+        }
+
+        let count = buffer.ready.len().min(max_spans);
+        buffer.ready.drain(..count).collect()
+    }
+
+    /// Queues the spans of kept traces every second until the process exits.
+    pub async fn run(&self, ingest_queue: &IngestQueue) {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+        loop {
+            interval.tick().await;
+
+            // Spans that don't fit are held until the queue has room, while `add`
+            // turns exports away.
+            let spans = self.take_decided(false, ingest_queue.span_room());
+            if !spans.is_empty()
+                && let Err(spans) = ingest_queue.try_push_spans(spans)
+            {
+                self.buffer.lock().unwrap().ready.splice(0..0, spans);
+            }
+        }
+    }
+
+    /// Decides every buffered trace and queues the kept ones, e.g. on shutdown.
+    pub fn drain(&self, ingest_queue: &IngestQueue) {
+        ingest_queue.push_spans(self.take_decided(true, usize::MAX));
+    }
+}
@@ -0,0 +1,431 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use axum::extract::{FromRef, FromRequestParts};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+
+use super::auth::{AuthenticatedKey, Scope};
+
+/// The tenant of requests that don't name one, and of data stored before tenants.
+pub const DEFAULT_TENANT: &str = "default";
+
+const MAX_TENANT_LENGTH: usize = 64;
+
+pub fn default_tenant() -> String {
+    DEFAULT_TENANT.to_string()
+}
+
+/// Parses `tenant=value` pairs separated by commas, e.g. `acme=500,globex=2000`.
+fn tenant_values_from_env(name: &str) -> HashMap<String, f64> {
+    let Ok(value) = std::env::var(name) else {
+        return HashMap::new();
+    };
+
+    value
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let (tenant, value) = entry
+                .split_once('=')
+                .unwrap_or_else(|| panic!("{name} entries must look like tenant=value"));
+            let value = value
+                .trim()
+                .parse()
+                .unwrap_or_else(|_| panic!("{name} must contain valid numbers"));
+            (tenant.trim().to_string(), value)
+        })
+        .collect()
+}
+
+/// How the tenant is picked and how much each tenant may send. Per-tenant limits
+/// replace the defaults for that tenant.
+#[derive(Clone, Debug)]
+pub struct TenancyConfig {
+    /// Header naming the tenant, for keys that aren't bound to one.
+    pub header: String,
+    pub spans_per_second: Option<f64>,
+    pub spans_per_second_by_tenant: HashMap<String, f64>,
+    pub logs_per_second: Option<f64>,
+    pub logs_per_second_by_tenant: HashMap<String, f64>,
+}
+
+impl TenancyConfig {
+    pub fn from_env() -> Self {
+        let header = std::env::var("TENANT_HEADER").unwrap_or_else(|_| "X-Scope-OrgID".into());
+        let limit_from_env = |name: &str| {
+            std::env::var(name).ok().map(|v| {
+                v.parse()
+                    .unwrap_or_else(|_| panic!("{name} must be a valid number"))
+            })
+        };
+
+        TenancyConfig {
+            header,
+            spans_per_second: limit_from_env("TENANT_SPANS_PER_SECOND_LIMIT"),
+            spans_per_second_by_tenant: tenant_values_from_env(
+                "TENANT_SPANS_PER_SECOND_LIMIT_BY_TENANT",
+            ),
+            logs_per_second: limit_from_env("TENANT_LOGS_PER_SECOND_LIMIT"),
+            logs_per_second_by_tenant: tenant_values_from_env(
+                "TENANT_LOGS_PER_SECOND_LIMIT_BY_TENANT",
+            ),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum TenantError {
+    #[error("tenant ids must be 1 to 64 letters, digits, '-', '_' or '.'")]
+    Invalid,
+    #[error("not allowed to act for this tenant")]
+    Forbidden,
+}
+
+impl IntoResponse for TenantError {
+    fn into_response(self) -> Response {
+        match self {
+            TenantError::Invalid => StatusCode::BAD_REQUEST,
+            TenantError::Forbidden => StatusCode::FORBIDDEN,
+        }
+        .into_response()
+    }
+}
+
+pub fn validate_tenant(tenant: &str) -> Result<(), TenantError> {
+    let valid = !tenant.is_empty()
+        && tenant.len() <= MAX_TENANT_LENGTH
+        && tenant
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+
+    if valid {
+        Ok(())
+    } else {
+        Err(TenantError::Invalid)
+    }
+}
+
+/// Token bucket holding up to one second's worth of items.
+struct Quota {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Quota {
+    /// Takes `count` tokens if the bucket has them, or is full, so exports larger than
+    /// a second's worth still get through once the bucket refills.
+    fn take(&mut self, count: usize, limit: f64, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit).min(limit);
+        self.last_refill = now;
+
+        let count = count as f64;
+        if self.tokens >= count.min(limit) {
+            self.tokens -= count;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn refund(&mut self, count: usize, limit: f64) {
+        self.tokens = (self.tokens + count as f64).min(limit);
+    }
+}
+
+#[derive(Default)]
+struct Quotas {
+    spans: HashMap<String, Quota>,
+    logs: HashMap<String, Quota>,
+}
+
+pub struct Tenancy {
+    config: TenancyConfig,
+    quotas: Mutex<Quotas>,
+}
+
+impl Tenancy {
+    pub fn new(config: TenancyConfig) -> Self {
+        Tenancy {
+            config,
+            quotas: Mutex::new(Quotas::default()),
+        }
+    }
+
+    /// The tenant a request acts for: the key's tenant when it's bound to one, else the
+    /// tenant header for admin keys. Everyone else, including every request while auth
+    /// is disabled, acts for [`DEFAULT_TENANT`], and naming another tenant is forbidden.
+    pub fn resolve(
+        &self,
+        headers: &HeaderMap,
+        key: Option<&AuthenticatedKey>,
+    ) -> Result<String, TenantError> {
+        let requested = match headers.get(&self.config.header) {
+            Some(value) => {
+                let tenant = value.to_str().map_err(|_| TenantError::Invalid)?;
+                validate_tenant(tenant)?;
+                Some(tenant)
+            }
+            None => None,
+        };
+
+        let bound = match key {
+            Some(AuthenticatedKey {
+                tenant_id: Some(tenant),
+                ..
+            }) => tenant.as_str(),
+            Some(key) if key.allows(Scope::Admin) => requested.unwrap_or(DEFAULT_TENANT),
+            _ => DEFAULT_TENANT,
+        };
+
+        match requested {
+            Some(tenant) if tenant != bound => Err(TenantError::Forbidden),
+            _ => Ok(bound.to_string()),
+        }
+    }
+
+    fn span_limit(&self, tenant: &str) -> Option<f64> {
+        self.config
+            .spans_per_second_by_tenant
+            .get(tenant)
+            .copied()
+            .or(self.config.spans_per_second)
+    }
+
+    fn log_limit(&self, tenant: &str) -> Option<f64> {
+        self.config
+            .logs_per_second_by_tenant
+            .get(tenant)
+            .copied()
+            .or(self.config.logs_per_second)
+    }
+
+    /// Counts `count` spans against the tenant's quota, returning whether they're allowed.
+    pub fn take_spans(&self, tenant: &str, count: usize) -> bool {
+        let mut quotas = self.quotas.lock().unwrap();
+        take(&mut quotas.spans, tenant, count, self.span_limit(tenant))
+    }
+
+    /// Gives back spans taken with [`Self::take_spans`] that weren't accepted after all.
+    pub fn refund_spans(&self, tenant: &str, count: usize) {
+        let mut quotas = self.quotas.lock().unwrap();
+        refund(&mut quotas.spans, tenant, count, self.span_limit(tenant));
+    }
+
+    /// Same as [`Self::take_spans`], for logs.
+    pub fn take_logs(&self, tenant: &str, count: usize) -> bool {
+        let mut quotas = self.quotas.lock().unwrap();
+        take(&mut quotas.logs, tenant, count, self.log_limit(tenant))
+    }
+
+    /// Same as [`Self::refund_spans`], for logs.
+    pub fn refund_logs(&self, tenant: &str, count: usize) {
+        let mut quotas = self.quotas.lock().unwrap();
+        refund(&mut quotas.logs, tenant, count, self.log_limit(tenant));
+    }
+}
+
+fn take(
+    quotas: &mut HashMap<String, Quota>,
+    tenant: &str,
+    count: usize,
+    limit: Option<f64>,
+) -> bool {
+    let Some(limit) = limit else {
+        return true;
+    };
+
+    let now = Instant::now();
+    quotas
+        .entry(tenant.to_string())
+        .or_insert(Quota {
+            tokens: limit,
+            last_refill: now,
+        })
+        .take(count, limit, now)
+}
+
+fn refund(quotas: &mut HashMap<String, Quota>, tenant: &str, count: usize, limit: Option<f64>) {
+    if let (Some(limit), Some(quota)) = (limit, quotas.get_mut(tenant)) {
+        quota.refund(count, limit);
+    }
+}
+
+/// The tenant a request acts for, see [`Tenancy::resolve`].
+pub struct Tenant(pub String);
+
+impl<S> FromRequestParts<S> for Tenant
+where
+    Arc<Tenancy>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = TenantError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let tenancy = Arc::<Tenancy>::from_ref(state);
+
+        tenancy
+            .resolve(&parts.headers, parts.extensions.get::<AuthenticatedKey>())
+            .map(Tenant)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn tenancy() -> Tenancy {
+        Tenancy::new(TenancyConfig {
+            header: "X-Scope-OrgID".to_string(),
+            spans_per_second: Some(10.0),
+            spans_per_second_by_tenant: HashMap::from([("big".to_string(), 1000.0)]),
+            logs_per_second: None,
+            logs_per_second_by_tenant: HashMap::new(),
+        })
+    }
+
+    fn headers(tenant: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(tenant) = tenant {
+            headers.insert("X-Scope-OrgID", tenant.parse().unwrap());
+        }
+        headers
+    }
+
+    fn key(scopes: &[Scope], tenant_id: Option<&str>) -> AuthenticatedKey {
+        AuthenticatedKey {
+            id: None,
+            scopes: scopes.to_vec(),
+            tenant_id: tenant_id.map(str::to_string),
+        }
+    }
+
+    fn resolve(
+        tenant: Option<&str>,
+        key: Option<&AuthenticatedKey>,
+    ) -> Result<String, TenantError> {
+        tenancy().resolve(&headers(tenant), key)
+    }
+
+    #[test]
+    fn without_auth_everything_is_the_default_tenant() {
+        assert_eq!(resolve(None, None), Ok(DEFAULT_TENANT.to_string()));
+        assert_eq!(
+            resolve(Some(DEFAULT_TENANT), None),
+            Ok(DEFAULT_TENANT.to_string())
+        );
+        assert_eq!(resolve(Some("acme"), None), Err(TenantError::Forbidden));
+    }
+
+    #[test]
+    fn bound_keys_act_for_their_tenant() {
+        let key = key(&[Scope::Admin], Some("acme"));
+
+        assert_eq!(resolve(None, Some(&key)), Ok("acme".to_string()));
+        assert_eq!(resolve(Some("acme"), Some(&key)), Ok("acme".to_string()));
+        assert_eq!(
+            resolve(Some("globex"), Some(&key)),
+            Err(TenantError::Forbidden)
+        );
+    }
+
+    #[test]
+    fn only_unbound_admin_keys_pick_a_tenant() {
+        let admin = key(&[Scope::Admin], None);
+        assert_eq!(resolve(Some("acme"), Some(&admin)), Ok("acme".to_string()));
+        assert_eq!(resolve(None, Some(&admin)), Ok(DEFAULT_TENANT.to_string()));
+
+        let reader = key(&[Scope::Read, Scope::Ingest], None);
+        assert_eq!(resolve(None, Some(&reader)), Ok(DEFAULT_TENANT.to_string()));
+        assert_eq!(
+            resolve(Some("acme"), Some(&reader)),
+            Err(TenantError::Forbidden)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_tenant_ids() {
+        let admin = key(&[Scope::Admin], None);
+
+        assert_eq!(
+            resolve(Some("a/b"), Some(&admin)),
+            Err(TenantError::Invalid)
+        );
+        assert_eq!(
+            resolve(Some(&"a".repeat(65)), Some(&admin)),
+            Err(TenantError::Invalid)
+        );
+        assert!(validate_tenant("team-1_eu.prod").is_ok());
+        assert!(validate_tenant("").is_err());
+    }
+
+    #[test]
+    fn quota_refills_at_the_limit_up_to_one_second() {
+        let start = Instant::now();
+        let mut quota = Quota {
+            tokens: 10.0,
+            last_refill: start,
+        };
+
+        assert!(quota.take(6, 10.0, start));
+        assert!(quota.take(4, 10.0, start));
+        assert!(!quota.take(1, 10.0, start));
+
+        // Half a second refills 5 tokens.
+        let later = start + Duration::from_millis(500);
+        assert!(!quota.take(6, 10.0, later));
+        assert!(quota.take(5, 10.0, later));
+
+        // Idle time never fills the bucket past the limit.
+        let much_later = later + Duration::from_secs(60);
+        assert!(quota.take(10, 10.0, much_later));
+        assert!(!quota.take(1, 10.0, much_later));
+    }
+
+    #[test]
+    fn quota_lets_large_exports_through_a_full_bucket() {
+        let start = Instant::now();
+        let mut quota = Quota {
+            tokens: 10.0,
+            last_refill: start,
+        };
+
+        assert!(quota.take(25, 10.0, start));
+        assert_eq!(quota.tokens, -15.0);
+
+        // The overdraft is paid back before anything else gets through.
+        assert!(!quota.take(1, 10.0, start + Duration::from_secs(1)));
+        assert!(quota.take(1, 10.0, start + Duration::from_millis(2600)));
+    }
+
+    #[test]
+    fn quotas_are_per_tenant() {
+        let tenancy = tenancy();
+
+        assert!(tenancy.take_spans("acme", 10));
+        assert!(!tenancy.take_spans("acme", 1));
+        assert!(tenancy.take_spans("globex", 10));
+        assert!(tenancy.take_spans("big", 500));
+        assert!(tenancy.take_logs("acme", 1_000_000));
+    }
+
+    #[test]
+    fn refunds_give_back_what_was_taken() {
+        let tenancy = tenancy();
+
+        assert!(tenancy.take_spans("acme", 10));
+        tenancy.refund_spans("acme", 10);
+        assert!(tenancy.take_spans("acme", 10));
+        assert!(!tenancy.take_spans("acme", 1));
+
+        // Refunds never fill the bucket past the limit.
+        tenancy.refund_spans("acme", 100);
+        assert!(tenancy.take_spans("acme", 10));
+        assert!(!tenancy.take_spans("acme", 1));
+    }
+}
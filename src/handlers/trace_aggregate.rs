@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::trace_tree::{TraceTree, TraceTreeNode};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DurationPercentiles {
+    pub p50_ns: i64,
+    pub p90_ns: i64,
+    pub p99_ns: i64,
+    pub max_ns: i64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AggregateTraceNode {
+    pub service_name: Option<String>,
+    pub operation_name: String,
+    /// Number of traces in which this call appears.
+    pub trace_count: usize,
+    /// Total number of calls across all traces.
+    pub call_count: usize,
+    pub calls_per_trace: f64,
+    pub error_count: usize,
+    pub duration: DurationPercentiles,
+    pub self_time: DurationPercentiles,
+    pub children: Vec<AggregateTraceNode>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AggregateTrace {
+    pub trace_count: usize,
+    pub roots: Vec<AggregateTraceNode>,
+}
+
+#[derive(Default)]
+struct NodeBuilder {
+    service_name: Option<String>,
+    operation_name: String,
+    traces: usize,
+    last_trace: Option<usize>,
+    error_count: usize,
+    durations: Vec<i64>,
+    self_times: Vec<i64>,
+    child_order: Vec<String>,
+    children: HashMap<String, NodeBuilder>,
+}
+
+/// Nearest-rank percentiles of `values`, which must be non-empty.
+fn percentiles(values: &mut [i64]) -> DurationPercentiles {
+    values.sort_unstable();
+    let rank = |p: f64| {
+        let index = ((p * values.len() as f64).ceil() as usize).max(1) - 1;
+        values[index.min(values.len() - 1)]
+    };
+
+    DurationPercentiles {
+        p50_ns: rank(0.5),
+        p90_ns: rank(0.9),
+        p99_ns: rank(0.99),
+        max_ns: values[values.len() - 1],
+    }
+}
+
+impl NodeBuilder {
+    fn add(&mut self, node: &TraceTreeNode, trace_index: usize) {
+        if self.last_trace != Some(trace_index) {
+            self.traces += 1;
+            self.last_trace = Some(trace_index);
+        }
+        if node.span.status_code == 2 {
+            self.error_count += 1;
+        }
+        self.durations.push(node.span.duration_ns);
+        self.self_times.push(node.self_time_ns);
+
+        for child in &node.children {
+            self.child(child).add(child, trace_index);
+        }
+    }
+
+    fn child(&mut self, node: &TraceTreeNode) -> &mut NodeBuilder {
+        let key = node.key();
+        if !self.children.contains_key(&key) {
+            self.child_order.push(key.clone());
+        }
+
+        self.children.entry(key).or_insert_with(|| NodeBuilder {
+            service_name: node.span.service_name.clone(),
+            operation_name: node.span.operation_name.clone(),
+            ..Default::default()
+        })
+    }
+
+    fn build_children(&mut self) -> Vec<AggregateTraceNode> {
+        self.child_order
+            .iter()
+            .filter_map(|key| self.children.remove(key))
+            .map(NodeBuilder::build)
+            .collect()
+    }
+
+    fn build(mut self) -> AggregateTraceNode {
+        let children = self.build_children();
+
+        AggregateTraceNode {
+            service_name: self.service_name,
+            operation_name: self.operation_name,
+            trace_count: self.traces,
+            call_count: self.durations.len(),
+            calls_per_trace: self.durations.len() as f64 / self.traces as f64,
+            error_count: self.error_count,
+            duration: percentiles(&mut self.durations),
+            self_time: percentiles(&mut self.self_times),
+            children,
+        }
+    }
+}
+
+/// Merges `trees` into one call tree, keyed by the `service:operation` path from the
+/// root. Only roots matching `service_name`/`operation_name` are merged.
+pub fn aggregate_trace_trees(
+    trees: &[TraceTree],
+    service_name: &str,
+    operation_name: &str,
+) -> AggregateTrace {
+    let mut root = NodeBuilder::default();
+    let mut trace_count = 0;
+
+    for (trace_index, tree) in trees.iter().enumerate() {
+        let mut matched = false;
+
+        for node in tree.roots.iter().filter(|n| {
+            !n.orphaned
+                && n.span.service_name.as_deref() == Some(service_name)
+                && n.span.operation_name == operation_name
+        }) {
+            root.child(node).add(node, trace_index);
+            matched = true;
+        }
+
+        if matched {
+            trace_count += 1;
+        }
+    }
+
+    AggregateTrace {
+        trace_count,
+        roots: root.build_children(),
+    }
+}
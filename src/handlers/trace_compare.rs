@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::trace_tree::{TraceTree, TraceTreeNode};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ComparisonStatus {
+    Matched,
+    /// Only present in the other trace.
+    Added,
+    /// Only present in the base trace.
+    Missing,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TraceComparisonNode {
+    pub service_name: Option<String>,
+    pub operation_name: String,
+    /// `service:operation` of every node from the root down to this one.
+    pub path: Vec<String>,
+    pub status: ComparisonStatus,
+    pub base_span_id: Option<String>,
+    pub other_span_id: Option<String>,
+    pub base_duration_ns: Option<i64>,
+    pub other_duration_ns: Option<i64>,
+    /// `other - base`, only set for matched nodes.
+    pub duration_delta_ns: Option<i64>,
+    pub self_time_delta_ns: Option<i64>,
+    pub children: Vec<TraceComparisonNode>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TraceComparison {
+    pub base_trace_id: String,
+    pub other_trace_id: String,
+    pub matched_count: usize,
+    pub added_count: usize,
+    pub missing_count: usize,
+    pub roots: Vec<TraceComparisonNode>,
+}
+
+fn one_sided(
+    node: &TraceTreeNode,
+    parent_path: &[String],
+    status: ComparisonStatus,
+) -> TraceComparisonNode {
+    let mut path = parent_path.to_vec();
+    path.push(node.key());
+
+    let children = node
+        .children
+        .iter()
+        .map(|child| one_sided(child, &path, status.clone()))
+        .collect();
+
+    let (base, other) = match status {
+        ComparisonStatus::Added => (None, Some(node)),
+        _ => (Some(node), None),
+    };
+
+    TraceComparisonNode {
+        service_name: node.span.service_name.clone(),
+        operation_name: node.span.operation_name.clone(),
+        path,
+        status,
+        base_span_id: base.map(|n| n.span.span_id.clone()),
+        other_span_id: other.map(|n| n.span.span_id.clone()),
+        base_duration_ns: base.map(|n| n.span.duration_ns),
+        other_duration_ns: other.map(|n| n.span.duration_ns),
+        duration_delta_ns: None,
+        self_time_delta_ns: None,
+        children,
+    }
+}
+
+fn matched(
+    base: &TraceTreeNode,
+    other: &TraceTreeNode,
+    parent_path: &[String],
+) -> TraceComparisonNode {
+    let mut path = parent_path.to_vec();
+    path.push(base.key());
+
+    let children = align(&base.children, &other.children, &path);
+
+    TraceComparisonNode {
+        service_name: base.span.service_name.clone(),
+        operation_name: base.span.operation_name.clone(),
+        path,
+        status: ComparisonStatus::Matched,
+        base_span_id: Some(base.span.span_id.clone()),
+        other_span_id: Some(other.span.span_id.clone()),
+        base_duration_ns: Some(base.span.duration_ns),
+        other_duration_ns: Some(other.span.duration_ns),
+        duration_delta_ns: Some(other.span.duration_ns - base.span.duration_ns),
+        self_time_delta_ns: Some(other.self_time_ns - base.self_time_ns),
+        children,
+    }
+}
+
+/// Pairs siblings by `service:operation`; repeated keys are paired by start order, so the
+/// third `SELECT orders` call in one trace lines up with the third in the other.
+fn align(
+    base: &[TraceTreeNode],
+    other: &[TraceTreeNode],
+    parent_path: &[String],
+) -> Vec<TraceComparisonNode> {
+    let mut other_by_key: HashMap<String, Vec<&TraceTreeNode>> = HashMap::new();
+    for node in other {
+        other_by_key.entry(node.key()).or_default().push(node);
+    }
+
+    let mut base_occurrences: HashMap<String, usize> = HashMap::new();
+    let mut nodes = Vec::new();
+
+    for node in base {
+        let key = node.key();
+        let occurrence = base_occurrences.entry(key.clone()).or_default();
+
+        match other_by_key.get(&key).and_then(|n| n.get(*occurrence)) {
+            Some(other_node) => nodes.push(matched(node, other_node, parent_path)),
+            None => nodes.push(one_sided(node, parent_path, ComparisonStatus::Missing)),
+        }
+
+        *occurrence += 1;
+    }
+
+    let mut other_occurrences: HashMap<String, usize> = HashMap::new();
+    for node in other {
+        let key = node.key();
+        let occurrence = other_occurrences.entry(key.clone()).or_default();
+
+        if *occurrence >= base_occurrences.get(&key).copied().unwrap_or(0) {
+            nodes.push(one_sided(node, parent_path, ComparisonStatus::Added));
+        }
+
+        *occurrence += 1;
+    }
+
+    nodes
+}
+
+fn count_statuses(nodes: &[TraceComparisonNode], counts: &mut (usize, usize, usize)) {
+    for node in nodes {
+        match node.status {
+            ComparisonStatus::Matched => counts.0 += 1,
+            ComparisonStatus::Added => counts.1 += 1,
+            ComparisonStatus::Missing => counts.2 += 1,
+        }
+        count_statuses(&node.children, counts);
+    }
+}
+
+pub fn compare_trace_trees(base: &TraceTree, other: &TraceTree) -> TraceComparison {
+    let roots = align(&base.roots, &other.roots, &[]);
+
+    let mut counts = (0, 0, 0);
+    count_statuses(&roots, &mut counts);
+    let (matched_count, added_count, missing_count) = counts;
+
+    TraceComparison {
+        base_trace_id: base.trace_id.clone(),
+        other_trace_id: other.trace_id.clone(),
+        matched_count,
+        added_count,
+        missing_count,
+        roots,
+    }
+}
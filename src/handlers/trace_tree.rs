@@ -0,0 +1,342 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use super::crud::WriteableSpan;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TraceTreeNode {
+    #[serde(flatten)]
+    pub span: WriteableSpan,
+    pub depth: usize,
+    pub child_count: usize,
+    /// Duration not covered by any child span.
+    pub self_time_ns: i64,
+    pub on_critical_path: bool,
+    /// Set when the span names a parent that isn't part of the trace.
+    pub orphaned: bool,
+    pub children: Vec<TraceTreeNode>,
+}
+
+impl TraceTreeNode {
+    /// `service:operation`, used to line nodes up across traces.
+    pub fn key(&self) -> String {
+        format!(
+            "{}:{}",
+            self.span.service_name.as_deref().unwrap_or_default(),
+            self.span.operation_name
+        )
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TraceTree {
+    pub trace_id: String,
+    pub span_count: usize,
+    pub orphaned_span_count: usize,
+    /// Span ids on the critical path, in the order they start.
+    pub critical_path: Vec<String>,
+    pub roots: Vec<TraceTreeNode>,
+}
+
+fn start_ns(span: &WriteableSpan) -> i128 {
+    span.start_time.unix_timestamp_nanos()
+}
+
+fn end_ns(span: &WriteableSpan) -> i128 {
+    span.end_time.unix_timestamp_nanos()
+}
+
+/// Parent/child links between spans of one trace, by index into `spans`.
+struct SpanGraph<'a> {
+    spans: &'a [WriteableSpan],
+    children: Vec<Vec<usize>>,
+    roots: Vec<usize>,
+    orphaned: Vec<bool>,
+}
+
+impl<'a> SpanGraph<'a> {
+    fn new(spans: &'a [WriteableSpan]) -> Self {
+        let index_by_id: HashMap<&str, usize> = spans
+            .iter()
+            .enumerate()
+            .map(|(i, span)| (span.span_id.as_str(), i))
+            .collect();
+
+        let mut children = vec![Vec::new(); spans.len()];
+        let mut roots = Vec::new();
+        let mut orphaned = vec![false; spans.len()];
+
+        for (i, span) in spans.iter().enumerate() {
+            match span.parent_span_id.as_deref() {
+                Some(parent_id) => match index_by_id.get(parent_id) {
+                    Some(&parent) if parent != i => children[parent].push(i),
+                    _ => {
+                        orphaned[i] = true;
+                        roots.push(i);
+                    }
+                },
+                None => roots.push(i),
+            }
+        }
+
+        // Spans caught in a parent cycle are never reached from a root; surface them as
+        // orphaned roots instead of dropping them.
+        let mut reachable = vec![false; spans.len()];
+        let mut stack = roots.clone();
+        while let Some(i) = stack.pop() {
+            if !reachable[i] {
+                reachable[i] = true;
+                stack.extend(&children[i]);
+            }
+        }
+        for i in 0..spans.len() {
+            if !reachable[i] {
+                orphaned[i] = true;
+                roots.push(i);
+                for child in children.iter_mut() {
+                    child.retain(|&c| c != i);
+                }
+                let mut stack = vec![i];
+                while let Some(j) = stack.pop() {
+                    if !reachable[j] {
+                        reachable[j] = true;
+                        stack.extend(&children[j]);
+                    }
+                }
+            }
+        }
+
+        let by_start = |a: &usize, b: &usize| start_ns(&spans[*a]).cmp(&start_ns(&spans[*b]));
+        for child_list in children.iter_mut() {
+            child_list.sort_by(by_start);
+        }
+        roots.sort_by(by_start);
+
+        SpanGraph {
+            spans,
+            children,
+            roots,
+            orphaned,
+        }
+    }
+
+    fn self_time_ns(&self, i: usize) -> i64 {
+        let span = &self.spans[i];
+        let (start, end) = (start_ns(span), end_ns(span));
+
+        let mut intervals: Vec<(i128, i128)> = self.children[i]
+            .iter()
+            .map(|&c| {
+                (
+                    start_ns(&self.spans[c]).max(start),
+                    end_ns(&self.spans[c]).min(end),
+                )
+            })
+            .filter(|(s, e)| s < e)
+            .collect();
+        intervals.sort();
+
+        let mut covered: i128 = 0;
+        let mut current: Option<(i128, i128)> = None;
+        for (s, e) in intervals {
+            current = match current {
+                Some((cs, ce)) if s <= ce => Some((cs, ce.max(e))),
+                Some((cs, ce)) => {
+                    covered += ce - cs;
+                    Some((s, e))
+                }
+                None => Some((s, e)),
+            };
+        }
+        if let Some((cs, ce)) = current {
+            covered += ce - cs;
+        }
+
+        ((end - start).max(0) - covered).max(0) as i64
+    }
+
+    /// Walks back from the end of span `i`, following the last child to finish before the
+    /// current point in time, then whatever finished before that child started, and so on.
+    fn critical_path(&self, i: usize, path: &mut Vec<usize>, visited: &mut HashSet<usize>) {
+        if !visited.insert(i) {
+            return;
+        }
+        path.push(i);
+
+        let mut cursor = end_ns(&self.spans[i]);
+        let mut by_end: Vec<usize> = self.children[i].clone();
+        by_end.sort_by_key(|&c| std::cmp::Reverse(end_ns(&self.spans[c])));
+
+        for c in by_end {
+            let child = &self.spans[c];
+            if start_ns(child) >= cursor {
+                continue;
+            }
+            self.critical_path(c, path, visited);
+            cursor = start_ns(child);
+        }
+    }
+
+    fn build_node(&self, i: usize, depth: usize, critical: &HashSet<usize>) -> TraceTreeNode {
+        let children: Vec<TraceTreeNode> = self.children[i]
+            .iter()
+            .map(|&c| self.build_node(c, depth + 1, critical))
+            .collect();
+
+        TraceTreeNode {
+            span: self.spans[i].clone(),
+            depth,
+            child_count: children.len(),
+            self_time_ns: self.self_time_ns(i),
+            on_critical_path: critical.contains(&i),
+            orphaned: self.orphaned[i],
+            children,
+        }
+    }
+}
+
+pub fn build_trace_tree(trace_id: String, spans: &[WriteableSpan]) -> TraceTree {
+    let graph = SpanGraph::new(spans);
+
+    // The critical path runs through the longest root, preferring real roots over
+    // orphaned fragments.
+    let main_root = graph
+        .roots
+        .iter()
+        .copied()
+        .max_by_key(|&r| (!graph.orphaned[r], end_ns(&spans[r]) - start_ns(&spans[r])));
+
+    let mut critical_path = Vec::new();
+    if let Some(root) = main_root {
+        graph.critical_path(root, &mut critical_path, &mut HashSet::new());
+    }
+    critical_path.sort_by_key(|&i| start_ns(&spans[i]));
+    let critical: HashSet<usize> = critical_path.iter().copied().collect();
+
+    let roots: Vec<TraceTreeNode> = graph
+        .roots
+        .iter()
+        .map(|&r| graph.build_node(r, 0, &critical))
+        .collect();
+
+    TraceTree {
+        trace_id,
+        span_count: spans.len(),
+        orphaned_span_count: graph.orphaned.iter().filter(|o| **o).count(),
+        critical_path: critical_path
+            .into_iter()
+            .map(|i| spans[i].span_id.clone())
+            .collect(),
+        roots,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::crud::DbSpanKind;
+    use time::Duration;
+    use time::macros::datetime;
+
+    fn span(id: &str, parent: Option<&str>, start_us: i64, end_us: i64) -> WriteableSpan {
+        let epoch = datetime!(2026-10-18 12:00 UTC);
+
+        WriteableSpan {
+            tenant_id: "default".to_string(),
+            span_id: id.to_string(),
+            trace_id: "trace".to_string(),
+            parent_span_id: parent.map(str::to_string),
+            operation_name: id.to_string(),
+            start_time: epoch + Duration::microseconds(start_us),
+            end_time: epoch + Duration::microseconds(end_us),
+            duration_ns: (end_us - start_us) * 1000,
+            status_code: 0,
+            status_message: None,
+            span_kind: DbSpanKind::Internal,
+            instrumentation_library: None,
+            service_name: Some("api".to_string()),
+            attributes: HashMap::new(),
+        }
+    }
+
+    fn find<'a>(nodes: &'a [TraceTreeNode], id: &str) -> Option<&'a TraceTreeNode> {
+        nodes.iter().find_map(|node| {
+            (node.span.span_id == id)
+                .then_some(node)
+                .or_else(|| find(&node.children, id))
+        })
+    }
+
+    #[test]
+    fn critical_path_skips_children_hidden_by_a_later_one() {
+        let spans = [
+            span("root", None, 0, 100),
+            span("short", Some("root"), 50, 60),
+            span("long", Some("root"), 10, 90),
+        ];
+
+        let tree = build_trace_tree("trace".to_string(), &spans);
+
+        assert_eq!(tree.critical_path, ["root", "long"]);
+        let root = &tree.roots[0];
+        assert_eq!(root.child_count, 2);
+        assert_eq!(root.children[0].span.span_id, "long");
+        assert_eq!(root.self_time_ns, 20_000);
+        assert!(!find(&tree.roots, "short").unwrap().on_critical_path);
+    }
+
+    #[test]
+    fn critical_path_follows_sequential_children() {
+        let spans = [
+            span("root", None, 0, 100),
+            span("first", Some("root"), 0, 40),
+            span("second", Some("root"), 40, 70),
+            span("nested", Some("second"), 45, 65),
+        ];
+
+        let tree = build_trace_tree("trace".to_string(), &spans);
+
+        assert_eq!(tree.critical_path, ["root", "first", "second", "nested"]);
+        assert_eq!(tree.roots[0].self_time_ns, 30_000);
+        assert_eq!(find(&tree.roots, "second").unwrap().self_time_ns, 10_000);
+    }
+
+    #[test]
+    fn missing_and_own_parents_become_orphaned_roots() {
+        let spans = [
+            span("root", None, 0, 100),
+            span("lost", Some("gone"), 10, 20),
+            span("selfish", Some("selfish"), 30, 40),
+        ];
+
+        let tree = build_trace_tree("trace".to_string(), &spans);
+
+        assert_eq!(tree.roots.len(), 3);
+        assert_eq!(tree.orphaned_span_count, 2);
+        assert!(!tree.roots[0].orphaned);
+        assert!(tree.roots[1].orphaned && tree.roots[2].orphaned);
+        assert_eq!(tree.critical_path, ["root"]);
+    }
+
+    #[test]
+    fn parent_cycles_keep_every_span() {
+        let spans = [
+            span("root", None, 0, 100),
+            span("a", Some("b"), 10, 50),
+            span("b", Some("a"), 20, 40),
+        ];
+
+        let tree = build_trace_tree("trace".to_string(), &spans);
+
+        assert_eq!(tree.span_count, 3);
+        assert_eq!(tree.orphaned_span_count, 1);
+        let a = find(&tree.roots, "a").unwrap();
+        assert!(a.orphaned);
+        assert_eq!(a.depth, 0);
+        assert_eq!(a.children[0].span.span_id, "b");
+        assert_eq!(a.children[0].depth, 1);
+        assert!(a.children[0].children.is_empty());
+        assert_eq!(tree.critical_path, ["root"]);
+    }
+}
@@ -1,11 +1,18 @@
 use sqlx::postgres::PgPoolOptions;
 use std::{net::SocketAddr, sync::Arc};
 use tokio::net::TcpListener;
-use tower_http::cors::CorsLayer;
-use tracing_subscriber;
 
 mod handlers;
-use handlers::{create_api_router, create_otel_router};
+mod partitions;
+mod retention;
+use handlers::{
+    ApiState, Auth, AuthConfig, CorsConfig, HeadSampler, HeadSamplingConfig, IngestQueue,
+    IngestQueueConfig, OtelState, ProcessorConfig, Processors, RedactionConfig, Redactor,
+    SpanValidationConfig, SpanValidator, Spool, SpoolConfig, TailSampler, TailSamplingConfig,
+    Tenancy, TenancyConfig, create_api_router, create_otel_router,
+};
+use partitions::PartitionConfig;
+use retention::RetentionConfig;
 
 #[tokio::main]
 async fn main() -> Result<(), sqlx::Error> {
@@ -25,10 +32,70 @@ async fn main() -> Result<(), sqlx::Error> {
 
     sqlx::migrate!("./migrations").run(&pool).await?;
 
+    let partition_config = PartitionConfig::from_env();
+    partitions::create_partitions(&pool, &partition_config).await?;
+
     let pool = Arc::new(pool);
 
-    let otel_router = create_otel_router(pool.clone());
-    let api_router = create_api_router(pool.clone()).layer(CorsLayer::permissive());
+    let retention_config = RetentionConfig::from_env();
+    tokio::spawn(partitions::run(
+        pool.clone(),
+        partition_config,
+        retention_config.clone(),
+    ));
+    if retention_config.is_enabled() {
+        tokio::spawn(retention::run(pool.clone(), retention_config));
+    }
+
+    let spool_config = SpoolConfig::from_env();
+    let spool = spool_config.dir.map(|dir| {
+        Spool::open(&dir, spool_config.max_bytes)
+            .expect("INGEST_SPOOL_DIR must be a writable directory")
+    });
+    let ingest_queue = Arc::new(IngestQueue::new(IngestQueueConfig::from_env(), spool));
+    {
+        let ingest_queue = ingest_queue.clone();
+        let pool = pool.clone();
+        tokio::spawn(async move { ingest_queue.run(&pool).await });
+    }
+
+    let tail_sampling_config = TailSamplingConfig::from_env();
+    let tail_sampler = tail_sampling_config.enabled.then(|| {
+        let tail_sampler = Arc::new(TailSampler::new(tail_sampling_config));
+        let runner = tail_sampler.clone();
+        let ingest_queue = ingest_queue.clone();
+        tokio::spawn(async move { runner.run(&ingest_queue).await });
+        tail_sampler
+    });
+
+    let head_sampler = Arc::new(HeadSampler::new(HeadSamplingConfig::from_env()));
+    let span_validator = Arc::new(SpanValidator::new(SpanValidationConfig::from_env()));
+
+    let auth = Arc::new(Auth::new(AuthConfig::from_env()));
+    let tenancy_config = TenancyConfig::from_env();
+    let tenant_header = tenancy_config.header.clone();
+    let tenancy = Arc::new(Tenancy::new(tenancy_config));
+
+    let otel_router = create_otel_router(OtelState {
+        pool: pool.clone(),
+        ingest_queue: ingest_queue.clone(),
+        head_sampler: head_sampler.clone(),
+        tail_sampler: tail_sampler.clone(),
+        span_validator: span_validator.clone(),
+        processors: Arc::new(Processors::new(ProcessorConfig::from_env())),
+        redactor: Arc::new(Redactor::new(RedactionConfig::from_env())),
+        auth: auth.clone(),
+        tenancy: tenancy.clone(),
+    });
+    let api_router = create_api_router(ApiState {
+        pool: pool.clone(),
+        ingest_queue: ingest_queue.clone(),
+        head_sampler,
+        span_validator,
+        auth,
+        tenancy,
+    })
+    .layer(CorsConfig::from_env().layer(&tenant_header));
 
     let otel_addr = SocketAddr::from(([0, 0, 0, 0], 4317));
     let otel_listener = TcpListener::bind(otel_addr).await.unwrap();
@@ -43,13 +110,41 @@ async fn main() -> Result<(), sqlx::Error> {
         axum::serve(
             otel_listener,
             otel_router.into_make_service_with_connect_info::<SocketAddr>(),
-        ),
+        )
+        .with_graceful_shutdown(shutdown_signal()),
         axum::serve(
             api_listener,
             api_router.into_make_service_with_connect_info::<SocketAddr>(),
         )
+        .with_graceful_shutdown(shutdown_signal())
     )
     .unwrap();
 
+    tracing::info!("Shutting down, writing queued data");
+    if let Some(tail_sampler) = &tail_sampler {
+        tail_sampler.drain(&ingest_queue);
+    }
+    ingest_queue.flush(&pool).await;
+
     Ok(())
 }
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
@@ -0,0 +1,147 @@
+use std::sync::Arc;
+
+use sqlx::PgPool;
+use time::{Duration, OffsetDateTime};
+
+use crate::retention::RetentionConfig;
+
+/// Tables range-partitioned by day, see `create_daily_partition` in the migrations.
+const SPAN_TABLES: [&str; 1] = ["span"];
+const LOG_TABLES: [&str; 2] = ["log", "log_attribute"];
+
+#[derive(Clone, Debug)]
+pub struct PartitionConfig {
+    /// Number of days ahead of today to keep partitions for.
+    pub precreate_days: i64,
+    pub interval: std::time::Duration,
+}
+
+impl PartitionConfig {
+    pub fn from_env() -> Self {
+        let precreate_days = std::env::var("PARTITION_PRECREATE_DAYS")
+            .map(|v| {
+                v.parse()
+                    .expect("PARTITION_PRECREATE_DAYS must be a valid number")
+            })
+            .unwrap_or(3);
+        let interval_secs = std::env::var("PARTITION_MAINTENANCE_INTERVAL_SECS")
+            .map(|v| {
+                v.parse()
+                    .expect("PARTITION_MAINTENANCE_INTERVAL_SECS must be a valid number")
+            })
+            .unwrap_or(3600);
+
+        PartitionConfig {
+            precreate_days,
+            interval: std::time::Duration::from_secs(interval_secs),
+        }
+    }
+}
+
+/// Creates partitions from yesterday up to `config.precreate_days` ahead, for every
+/// partitioned table. Existing partitions are left alone.
+pub async fn create_partitions(pool: &PgPool, config: &PartitionConfig) -> Result<(), sqlx::Error> {
+    let today = OffsetDateTime::now_utc().date();
+
+    for table in SPAN_TABLES.iter().chain(LOG_TABLES.iter()) {
+        for offset in -1..=config.precreate_days {
+            sqlx::query!(
+                "SELECT create_daily_partition($1, $2)",
+                table,
+                today + Duration::days(offset),
+            )
+            .execute(pool)
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Keeps partitions ahead of time and drops expired ones every `config.interval` until
+/// the process exits.
+pub async fn run(pool: Arc<PgPool>, config: PartitionConfig, retention: RetentionConfig) {
+    let mut interval = tokio::time::interval(config.interval);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = create_partitions(&pool, &config).await {
+            tracing::error!("Creating partitions failed: {}", e);
+        }
+        if let Err(e) = drop_expired_partitions(&pool, &retention).await {
+            tracing::error!("Dropping expired partitions failed: {}", e);
+        }
+    }
+}
+
+async fn drop_expired_partitions(
+    pool: &PgPool,
+    retention: &RetentionConfig,
+) -> Result<(), sqlx::Error> {
+    let now = OffsetDateTime::now_utc();
+
+    if let Some(retention) = retention.span_partition_retention() {
+        for table in SPAN_TABLES {
+            drop_partitions_before(pool, table, now - retention).await?;
+        }
+    }
+    if let Some(retention) = retention.log_partition_retention() {
+        for table in LOG_TABLES {
+            drop_partitions_before(pool, table, now - retention).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Drops the daily partitions of `table` that end at or before `cutoff`. Each is
+/// detached concurrently first, so queries on `table` aren't blocked while it goes.
+async fn drop_partitions_before(
+    pool: &PgPool,
+    table: &str,
+    cutoff: OffsetDateTime,
+) -> Result<(), sqlx::Error> {
+    let partitions = sqlx::query!(
+        r#"
+        SELECT c.relname::TEXT AS "name!", i.inhdetachpending AS "detach_pending!"
+        FROM pg_inherits i
+        JOIN pg_class c ON c.oid = i.inhrelid
+        WHERE
+            i.inhparent = $1::TEXT::REGCLASS
+            AND c.relname ~ '_p[0-9]{8}$'
+            AND TO_DATE(RIGHT(c.relname, 8), 'YYYYMMDD') + 1
+                <= ($2::TIMESTAMPTZ AT TIME ZONE 'UTC')::DATE
+        ORDER BY 1
+        "#,
+        table,
+        cutoff,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for partition in partitions {
+        // A detach interrupted part way is left pending and can only be finalized.
+        let mode = if partition.detach_pending {
+            "FINALIZE"
+        } else {
+            "CONCURRENTLY"
+        };
+
+        // `partition.name` is an existing table name matching `<table>_pYYYYMMDD`.
+        // Detaching concurrently can't run in a transaction, so it goes through the
+        // simple query protocol.
+        let detach = format!(
+            r#"ALTER TABLE "{table}" DETACH PARTITION "{}" {mode}"#,
+            partition.name
+        );
+        sqlx::raw_sql(&detach).execute(pool).await?;
+        sqlx::query(&format!(r#"DROP TABLE "{}""#, partition.name))
+            .execute(pool)
+            .await?;
+
+        tracing::info!("Dropped expired partition {}", partition.name);
+    }
+
+    Ok(())
+}
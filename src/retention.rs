@@ -0,0 +1,479 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use sqlx::PgPool;
+use time::{Duration, OffsetDateTime};
+
+/// How long spans and logs are kept, globally, per tenant and per `service_name`. A
+/// per-tenant retention replaces the global one for that tenant, and a per-service
+/// retention replaces both for that service, whether shorter or longer.
+#[derive(Clone, Debug, Default)]
+pub struct RetentionConfig {
+    pub trace_retention: Option<Duration>,
+    pub log_retention: Option<Duration>,
+    pub trace_retention_by_tenant: HashMap<String, Duration>,
+    pub log_retention_by_tenant: HashMap<String, Duration>,
+    pub trace_retention_by_service: HashMap<String, Duration>,
+    pub log_retention_by_service: HashMap<String, Duration>,
+    pub batch_size: i64,
+    pub interval: std::time::Duration,
+}
+
+fn hours_from_env(name: &str) -> Option<Duration> {
+    std::env::var(name).ok().map(|v| {
+        Duration::hours(
+            v.parse::<i64>()
+                .unwrap_or_else(|_| panic!("{name} must be a valid number of hours")),
+        )
+    })
+}
+
+/// Parses `service=hours` or `tenant=hours` pairs separated by commas, e.g.
+/// `checkout=24,search=168`.
+fn hours_by_name_from_env(name: &str) -> HashMap<String, Duration> {
+    let Ok(value) = std::env::var(name) else {
+        return HashMap::new();
+    };
+
+    value
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let (key, hours) = entry
+                .split_once('=')
+                .unwrap_or_else(|| panic!("{name} entries must look like name=hours"));
+            let hours = hours
+                .trim()
+                .parse::<i64>()
+                .unwrap_or_else(|_| panic!("{name} must contain valid numbers of hours"));
+            (key.trim().to_string(), Duration::hours(hours))
+        })
+        .collect()
+}
+
+impl RetentionConfig {
+    pub fn from_env() -> Self {
+        let batch_size = std::env::var("RETENTION_BATCH_SIZE")
+            .map(|v| {
+                v.parse()
+                    .expect("RETENTION_BATCH_SIZE must be a valid number")
+            })
+            .unwrap_or(5000);
+        let interval_secs = std::env::var("RETENTION_INTERVAL_SECS")
+            .map(|v| {
+                v.parse()
+                    .expect("RETENTION_INTERVAL_SECS must be a valid number")
+            })
+            .unwrap_or(300);
+
+        RetentionConfig {
+            trace_retention: hours_from_env("TRACE_RETENTION_HOURS"),
+            log_retention: hours_from_env("LOG_RETENTION_HOURS"),
+            trace_retention_by_tenant: hours_by_name_from_env("TRACE_RETENTION_HOURS_BY_TENANT"),
+            log_retention_by_tenant: hours_by_name_from_env("LOG_RETENTION_HOURS_BY_TENANT"),
+            trace_retention_by_service: hours_by_name_from_env("TRACE_RETENTION_HOURS_BY_SERVICE"),
+            log_retention_by_service: hours_by_name_from_env("LOG_RETENTION_HOURS_BY_SERVICE"),
+            batch_size,
+            interval: std::time::Duration::from_secs(interval_secs),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.trace_retention.is_some()
+            || self.log_retention.is_some()
+            || !self.trace_retention_by_tenant.is_empty()
+            || !self.log_retention_by_tenant.is_empty()
+            || !self.trace_retention_by_service.is_empty()
+            || !self.log_retention_by_service.is_empty()
+    }
+
+    /// Age past which every span has expired, so whole partitions can be dropped. `None`
+    /// when spans of tenants and services without an override are kept forever.
+    pub fn span_partition_retention(&self) -> Option<Duration> {
+        self.trace_retention.map(|global| {
+            self.trace_retention_by_tenant
+                .values()
+                .chain(self.trace_retention_by_service.values())
+                .fold(global, |a, b| a.max(*b))
+        })
+    }
+
+    /// Same as [`Self::span_partition_retention`], for logs.
+    pub fn log_partition_retention(&self) -> Option<Duration> {
+        self.log_retention.map(|global| {
+            self.log_retention_by_tenant
+                .values()
+                .chain(self.log_retention_by_service.values())
+                .fold(global, |a, b| a.max(*b))
+        })
+    }
+
+    fn shortest_trace_retention(&self) -> Option<Duration> {
+        self.trace_retention
+            .iter()
+            .chain(self.trace_retention_by_tenant.values())
+            .chain(self.trace_retention_by_service.values())
+            .min()
+            .copied()
+    }
+}
+
+/// Purges expired data every `config.interval` until the process exits.
+pub async fn run(pool: Arc<PgPool>, config: RetentionConfig) {
+    let mut interval = tokio::time::interval(config.interval);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = purge(&pool, &config).await {
+            tracing::error!("Retention purge failed: {}", e);
+        }
+    }
+}
+
+async fn purge(pool: &PgPool, config: &RetentionConfig) -> Result<(), sqlx::Error> {
+    let now = OffsetDateTime::now_utc();
+    let mut deleted_spans = 0;
+    let mut deleted_rollups = 0;
+    let mut deleted_logs = 0;
+
+    let services: Vec<String> = config.trace_retention_by_service.keys().cloned().collect();
+    let tenants: Vec<String> = config.trace_retention_by_tenant.keys().cloned().collect();
+    let mut span_scopes: Vec<(DeleteScope, Duration)> = Vec::new();
+    for (service_name, retention) in &config.trace_retention_by_service {
+        span_scopes.push((DeleteScope::service(service_name), *retention));
+    }
+    for (tenant_id, retention) in &config.trace_retention_by_tenant {
+        span_scopes.push((DeleteScope::tenant(tenant_id, &services), *retention));
+    }
+    if let Some(retention) = config.trace_retention {
+        span_scopes.push((DeleteScope::rest(&services, &tenants), retention));
+    }
+    let mut deleted_catalog_entries = 0;
+    for (scope, retention) in &span_scopes {
+        deleted_spans += delete_spans(pool, now - *retention, scope, config.batch_size).await?;
+        deleted_rollups += delete_rollups(pool, now - *retention, scope, config.batch_size).await?;
+        // Catalog entries go once nothing they were seen on can be left.
+        deleted_catalog_entries +=
+            delete_stale_catalog_entries(pool, now - *retention, scope).await?;
+    }
+
+    let deleted_traces = match config.shortest_trace_retention() {
+        Some(retention) => delete_empty_traces(pool, now - retention, config.batch_size).await?,
+        None => 0,
+    };
+
+    let services: Vec<String> = config.log_retention_by_service.keys().cloned().collect();
+    let tenants: Vec<String> = config.log_retention_by_tenant.keys().cloned().collect();
+    for (service_name, retention) in &config.log_retention_by_service {
+        let scope = DeleteScope::service(service_name);
+        deleted_logs += delete_logs(pool, now - *retention, &scope, config.batch_size).await?;
+    }
+    for (tenant_id, retention) in &config.log_retention_by_tenant {
+        let scope = DeleteScope::tenant(tenant_id, &services);
+        deleted_logs += delete_logs(pool, now - *retention, &scope, config.batch_size).await?;
+    }
+    if let Some(retention) = config.log_retention {
+        let scope = DeleteScope::rest(&services, &tenants);
+        deleted_logs += delete_logs(pool, now - retention, &scope, config.batch_size).await?;
+    }
+
+    if deleted_spans + deleted_traces + deleted_logs + deleted_rollups + deleted_catalog_entries > 0
+    {
+        tracing::info!(
+            "Retention purge deleted {} spans, {} traces, {} logs, {} rollup buckets and {} catalog entries",
+            deleted_spans,
+            deleted_traces,
+            deleted_logs,
+            deleted_rollups,
+            deleted_catalog_entries
+        );
+    }
+
+    Ok(())
+}
+
+/// The rows a retention applies to: one service, one tenant except the services with
+/// their own retention, or everything without a retention of its own.
+struct DeleteScope<'a> {
+    service_name: Option<&'a str>,
+    excluded_services: &'a [String],
+    tenant_id: Option<&'a str>,
+    excluded_tenants: &'a [String],
+}
+
+impl<'a> DeleteScope<'a> {
+    fn service(service_name: &'a str) -> Self {
+        DeleteScope {
+            service_name: Some(service_name),
+            excluded_services: &[],
+            tenant_id: None,
+            excluded_tenants: &[],
+        }
+    }
+
+    fn tenant(tenant_id: &'a str, excluded_services: &'a [String]) -> Self {
+        DeleteScope {
+            service_name: None,
+            excluded_services,
+            tenant_id: Some(tenant_id),
+            excluded_tenants: &[],
+        }
+    }
+
+    fn rest(excluded_services: &'a [String], excluded_tenants: &'a [String]) -> Self {
+        DeleteScope {
+            service_name: None,
+            excluded_services,
+            tenant_id: None,
+            excluded_tenants,
+        }
+    }
+}
+
+/// Deletes spans in `scope` that started before `cutoff`. Each batch commits on its
+/// own to keep locks short.
+async fn delete_spans(
+    pool: &PgPool,
+    cutoff: OffsetDateTime,
+    scope: &DeleteScope<'_>,
+    batch_size: i64,
+) -> Result<u64, sqlx::Error> {
+    let mut total = 0;
+
+    loop {
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM span
+            WHERE (tenant_id, trace_id, id, started_at) IN (
+                SELECT tenant_id, trace_id, id, started_at
+                FROM span
+                WHERE
+                    started_at < $1
+                    AND ($2::TEXT IS NULL OR service_name = $2::TEXT)
+                    AND (
+                        $2::TEXT IS NOT NULL
+                        OR service_name IS NULL
+                        OR NOT (service_name = ANY($3::TEXT[]))
+                    )
+                    AND ($4::TEXT IS NULL OR tenant_id = $4::TEXT)
+                    AND NOT (tenant_id = ANY($5::TEXT[]))
+                LIMIT $6
+            )
+            "#,
+            cutoff,
+            scope.service_name,
+            scope.excluded_services,
+            scope.tenant_id,
+            scope.excluded_tenants,
+            batch_size,
+        )
+        .execute(pool)
+        .await?;
+
+        total += result.rows_affected();
+        if result.rows_affected() < batch_size as u64 {
+            return Ok(total);
+        }
+    }
+}
+
+/// Deletes the rollups of spans in `scope` that started before `cutoff`, keeping the
+/// minute `cutoff` falls in, which may still have spans.
+async fn delete_rollups(
+    pool: &PgPool,
+    cutoff: OffsetDateTime,
+    scope: &DeleteScope<'_>,
+    batch_size: i64,
+) -> Result<u64, sqlx::Error> {
+    let mut total = 0;
+
+    loop {
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM span_rollup_1m
+            WHERE (tenant_id, bucket, service_name, operation_name, kind) IN (
+                SELECT tenant_id, bucket, service_name, operation_name, kind
+                FROM span_rollup_1m
+                WHERE
+                    bucket <= $1::TIMESTAMPTZ - INTERVAL '1 minute'
+                    AND ($2::TEXT IS NULL OR service_name = $2::TEXT)
+                    AND ($2::TEXT IS NOT NULL OR NOT (service_name = ANY($3::TEXT[])))
+                    AND ($4::TEXT IS NULL OR tenant_id = $4::TEXT)
+                    AND NOT (tenant_id = ANY($5::TEXT[]))
+                LIMIT $6
+            )
+            "#,
+            cutoff,
+            scope.service_name,
+            scope.excluded_services,
+            scope.tenant_id,
+            scope.excluded_tenants,
+            batch_size,
+        )
+        .execute(pool)
+        .await?;
+
+        total += result.rows_affected();
+        if result.rows_affected() < batch_size as u64 {
+            return Ok(total);
+        }
+    }
+}
+
+/// Deletes services, operations and attribute keys in `scope` last seen before `cutoff`.
+async fn delete_stale_catalog_entries(
+    pool: &PgPool,
+    cutoff: OffsetDateTime,
+    scope: &DeleteScope<'_>,
+) -> Result<u64, sqlx::Error> {
+    let services = sqlx::query!(
+        r#"
+        DELETE FROM service_catalog
+        WHERE
+            last_seen < $1
+            AND ($2::TEXT IS NULL OR service_name = $2::TEXT)
+            AND ($2::TEXT IS NOT NULL OR NOT (service_name = ANY($3::TEXT[])))
+            AND ($4::TEXT IS NULL OR tenant_id = $4::TEXT)
+            AND NOT (tenant_id = ANY($5::TEXT[]))
+        "#,
+        cutoff,
+        scope.service_name,
+        scope.excluded_services,
+        scope.tenant_id,
+        scope.excluded_tenants,
+    )
+    .execute(pool)
+    .await?;
+    let operations = sqlx::query!(
+        r#"
+        DELETE FROM operation_catalog
+        WHERE
+            last_seen < $1
+            AND ($2::TEXT IS NULL OR service_name = $2::TEXT)
+            AND ($2::TEXT IS NOT NULL OR NOT (service_name = ANY($3::TEXT[])))
+            AND ($4::TEXT IS NULL OR tenant_id = $4::TEXT)
+            AND NOT (tenant_id = ANY($5::TEXT[]))
+        "#,
+        cutoff,
+        scope.service_name,
+        scope.excluded_services,
+        scope.tenant_id,
+        scope.excluded_tenants,
+    )
+    .execute(pool)
+    .await?;
+    let attributes = sqlx::query!(
+        r#"
+        DELETE FROM span_attribute_catalog
+        WHERE
+            last_seen < $1
+            AND ($2::TEXT IS NULL OR service_name = $2::TEXT)
+            AND ($2::TEXT IS NOT NULL OR NOT (service_name = ANY($3::TEXT[])))
+            AND ($4::TEXT IS NULL OR tenant_id = $4::TEXT)
+            AND NOT (tenant_id = ANY($5::TEXT[]))
+        "#,
+        cutoff,
+        scope.service_name,
+        scope.excluded_services,
+        scope.tenant_id,
+        scope.excluded_tenants,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(services.rows_affected() + operations.rows_affected() + attributes.rows_affected())
+}
+
+/// Deletes traces that ended before `cutoff` and have no spans left.
+async fn delete_empty_traces(
+    pool: &PgPool,
+    cutoff: OffsetDateTime,
+    batch_size: i64,
+) -> Result<u64, sqlx::Error> {
+    let mut total = 0;
+
+    loop {
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM trace
+            WHERE (tenant_id, id) IN (
+                SELECT t.tenant_id, t.id
+                FROM trace t
+                WHERE
+                    t.ended_at < $1
+                    AND NOT EXISTS (
+                        SELECT 1 FROM span s
+                        WHERE s.tenant_id = t.tenant_id AND s.trace_id = t.id
+                    )
+                LIMIT $2
+            )
+            "#,
+            cutoff,
+            batch_size,
+        )
+        .execute(pool)
+        .await?;
+
+        total += result.rows_affected();
+        if result.rows_affected() < batch_size as u64 {
+            return Ok(total);
+        }
+    }
+}
+
+/// Deletes logs and their attributes in `scope` older than `cutoff`.
+async fn delete_logs(
+    pool: &PgPool,
+    cutoff: OffsetDateTime,
+    scope: &DeleteScope<'_>,
+    batch_size: i64,
+) -> Result<u64, sqlx::Error> {
+    let mut total = 0;
+
+    loop {
+        let deleted = sqlx::query_scalar!(
+            r#"
+            WITH deleted_logs AS (
+                DELETE FROM log
+                WHERE (tenant_id, id, timestamp) IN (
+                    SELECT tenant_id, id, timestamp
+                    FROM log
+                    WHERE
+                        timestamp < $1
+                        AND ($2::TEXT IS NULL OR service_name = $2::TEXT)
+                        AND (
+                            $2::TEXT IS NOT NULL
+                            OR service_name IS NULL
+                            OR NOT (service_name = ANY($3::TEXT[]))
+                        )
+                        AND ($4::TEXT IS NULL OR tenant_id = $4::TEXT)
+                        AND NOT (tenant_id = ANY($5::TEXT[]))
+                    LIMIT $6
+                )
+                RETURNING tenant_id, id, timestamp
+            ),
+            deleted_attributes AS (
+                DELETE FROM log_attribute
+                WHERE (tenant_id, log_id, timestamp) IN (
+                    SELECT tenant_id, id, timestamp FROM deleted_logs
+                )
+            )
+            SELECT COUNT(*) AS "count!" FROM deleted_logs
+            "#,
+            cutoff,
+            scope.service_name,
+            scope.excluded_services,
+            scope.tenant_id,
+            scope.excluded_tenants,
+            batch_size,
+        )
+        .fetch_one(pool)
+        .await? as u64;
+
+        total += deleted;
+        if deleted < batch_size as u64 {
+            return Ok(total);
+        }
+    }
+}